@@ -1,6 +1,9 @@
-use crate::nes::Nes;
+use crate::bus::Bus;
+use crate::cpu::Cpu6502;
+use crate::cycle::Sequence;
+use crate::opcode::Opcode;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AddressingMode {
     Implied,
     Accumulator,
@@ -17,113 +20,174 @@ pub enum AddressingMode {
     IndirectY,
 }
 
-impl Nes {
-    pub fn implied(&mut self) -> u16 {
-        self.cpu.pc += 1;
-        0
+impl AddressingMode {
+    // length of an instruction using this mode, including the opcode byte
+    pub const fn bytes(self) -> u8 {
+        match self {
+            AddressingMode::Implied | AddressingMode::Accumulator => 1,
+            AddressingMode::Immediate
+            | AddressingMode::ZeroPage
+            | AddressingMode::ZeroPageX
+            | AddressingMode::ZeroPageY
+            | AddressingMode::Relative
+            | AddressingMode::IndirectX
+            | AddressingMode::IndirectY => 2,
+            AddressingMode::Absolute
+            | AddressingMode::AbsoluteX
+            | AddressingMode::AbsoluteY
+            | AddressingMode::Indirect => 3,
+        }
     }
+}
 
-    pub fn accumulator(&mut self) -> u16 {
-        self.cpu.pc += 1;
-        self.cpu.a as u16
-    }
-
-    pub fn immediate(&mut self) -> u16 {
-        let addr = self.cpu.pc + 1;
-        self.cpu.pc += 2;
-        addr
-    }
-
-    pub fn zero_page(&mut self) -> u16 {
-        let lower = self.fetch_code8(1);
-        let upper = 0x00;
-        let addr = upper << 8 | lower as u16;
-
-        self.cpu.pc += 2;
-        addr
-    }
-
-    pub fn zero_page_x(&mut self) -> u16 {
-        let lower = self.fetch_code8(1) + self.cpu.x;
-        let upper = 0x00;
-        let addr = (upper as u16) << 8 | lower as u16;
-
-        self.cpu.pc += 2;
-        addr
-    }
-
-    pub fn zero_page_y(&mut self) -> u16 {
-        let lower = self.fetch_code8(1) + self.cpu.y;
-        let upper = 0x00;
-        let addr = (upper as u16) << 8 | lower as u16;
-
-        self.cpu.pc += 2;
-        addr
+// Resolves the effective address one bus cycle at a time. Once it is known,
+// `operand` is set to the cycle that accesses it.
+impl<B: Bus> Cpu6502<B> {
+    // Runs cycle `t` of the address phase and returns whether it finished
+    // the instruction, which only happens for reads that skip the fix-up.
+    pub(crate) fn address_cycle(&mut self, s: &mut Sequence, opcode: Opcode) -> bool {
+        let mode = opcode.mode;
+        match (mode, s.t) {
+            (_, 1) => {
+                s.addr = self.fetch_pc() as u16;
+                if mode == AddressingMode::ZeroPage {
+                    s.operand = Some(2)
+                }
+            }
+            // Zero page indexing never leaves page zero: $ff,X with X=1 is $00.
+            (AddressingMode::ZeroPageX, _) | (AddressingMode::ZeroPageY, _) => {
+                self.fetch_memory8(s.addr);
+                s.addr = (s.addr as u8).wrapping_add(self.index(mode)) as u16;
+                s.operand = Some(3)
+            }
+            (AddressingMode::Absolute, _) => {
+                s.addr |= (self.fetch_pc() as u16) << 8;
+                s.operand = Some(3)
+            }
+            (AddressingMode::AbsoluteX, 2) | (AddressingMode::AbsoluteY, 2) => {
+                let base = s.addr | (self.fetch_pc() as u16) << 8;
+                self.add_index(s, base, self.index(mode))
+            }
+            (AddressingMode::AbsoluteX, _) | (AddressingMode::AbsoluteY, _) => {
+                return self.fix_up(s, opcode)
+            }
+            // Pointers stored in zero page wrap around: the upper byte of a
+            // pointer at $ff comes from $00.
+            (AddressingMode::IndirectX, 2) => {
+                self.fetch_memory8(s.addr);
+                s.value = (s.addr as u8).wrapping_add(self.cpu.x);
+            }
+            (AddressingMode::IndirectX, 3) => {
+                s.addr = self.fetch_memory8(s.value as u16) as u16;
+            }
+            (AddressingMode::IndirectX, _) => {
+                let upper = self.fetch_memory8(s.value.wrapping_add(1) as u16);
+                s.addr |= (upper as u16) << 8;
+                s.operand = Some(5)
+            }
+            (AddressingMode::IndirectY, 2) => {
+                s.value = s.addr as u8;
+                s.addr = self.fetch_memory8(s.addr) as u16;
+            }
+            (AddressingMode::IndirectY, 3) => {
+                let upper = self.fetch_memory8(s.value.wrapping_add(1) as u16);
+                let base = s.addr | (upper as u16) << 8;
+                self.add_index(s, base, self.cpu.y)
+            }
+            (AddressingMode::IndirectY, _) => return self.fix_up(s, opcode),
+            _ => unreachable!("{:?} has no address phase", mode),
+        }
+        false
     }
 
-    pub fn absolute(&mut self) -> u16 {
-        let lower = self.fetch_code8(1);
-        let upper = self.fetch_code8(2);
-        let addr = (upper as u16) << 8 | lower as u16;
-
-        self.cpu.pc += 3;
-        addr
+    fn index(&self, mode: AddressingMode) -> u8 {
+        match mode {
+            AddressingMode::ZeroPageX | AddressingMode::AbsoluteX => self.cpu.x,
+            _ => self.cpu.y,
+        }
     }
 
-    pub fn absolute_x(&mut self) -> u16 {
-        let lower = self.fetch_code8(1);
-        let upper = self.fetch_code8(2);
-        let addr = (upper as u16) << 8 | (lower + self.cpu.x) as u16;
-
-        self.cpu.pc += 3;
-        addr
+    fn add_index(&mut self, s: &mut Sequence, base: u16, index: u8) {
+        s.addr = base.wrapping_add(index as u16);
+        s.page_crossed = s.addr & 0xff00 != base & 0xff00;
     }
 
-    pub fn absolute_y(&mut self) -> u16 {
-        let lower = self.fetch_code8(1);
-        let upper = self.fetch_code8(2);
-        let addr = (upper as u16) << 8 | (lower + self.cpu.y) as u16;
-
-        self.cpu.pc += 3;
-        addr
+    // The CPU first adds the index to the lower byte only and reads from that
+    // address while it fixes up the upper byte. When no page was crossed that
+    // read already is the operand, so reads finish here; writes and
+    // read-modify-write instructions always spend the cycle.
+    fn fix_up(&mut self, s: &mut Sequence, opcode: Opcode) -> bool {
+        let addr = if s.page_crossed {
+            s.addr.wrapping_sub(0x0100)
+        } else {
+            s.addr
+        };
+        let value = self.fetch_memory8(addr);
+        if opcode.page_penalty() && !s.page_crossed {
+            self.read_op(opcode.instruction, value);
+            return true;
+        }
+        s.operand = Some(s.t + 1);
+        false
     }
+}
 
-    pub fn relative(&mut self) -> u16 {
-        let delta = self.fetch_code8(1);
-        self.cpu.pc += 2;
-        let addr = self.cpu.pc as i32 + (delta as i8) as i32;
-        addr as u16
+#[cfg(test)]
+mod test {
+    use crate::asm::assemble;
+    use crate::nes::Nes;
+
+    // Assembles `source` at $0200, runs it and returns the cycles it took.
+    fn run(source: &str, nes: &mut Nes) -> u16 {
+        let program = assemble(source, 0x0200).unwrap();
+        nes.bus.ram[0x0200..0x0200 + program.bytes.len()].copy_from_slice(&program.bytes);
+        nes.cpu.pc = 0x0200;
+        let mut cycles = 0;
+        while (nes.cpu.pc as usize) < 0x0200 + program.bytes.len() {
+            cycles += nes.step().unwrap().cycles;
+        }
+        cycles
     }
 
-    pub fn indexed_indirect(&mut self) -> u16 {
-        let lower = self.fetch_code8(1) + self.cpu.x;
-        let upper = 0x00;
-        let addr = (upper << 8 as u16) | lower as u16;
-        let lower = self.fetch_memory8(addr as u16);
-        let upper = self.fetch_memory8((addr + 1) as u16);
-        let addr = (upper as u16) << 8 | lower as u16;
-
-        self.cpu.pc += 2;
-        addr
+    #[test]
+    fn test_jmp_indirect_page_wrap() {
+        let mut nes = Nes::default();
+        nes.bus.ram[0x02ff] = 0x34;
+        nes.bus.ram[0x0200] = 0x12;
+        nes.bus.ram[0x0300] = 0x56;
+        nes.bus.ram[0x0400..0x0403].copy_from_slice(&[0x6c, 0xff, 0x02]);
+        nes.cpu.pc = 0x0400;
+        nes.step().unwrap();
+        assert_eq!(nes.cpu.pc, 0x1234);
     }
 
-    pub fn indirect_indexed(&mut self) -> u16 {
-        let lower = self.fetch_code8(1) + self.cpu.x;
-        let upper = 0x00;
-        let addr = (upper as u16) << 8 | lower as u16;
-        let lower = self.fetch_memory8(addr as u16);
-        let upper = self.fetch_memory8(addr as u16 + 1);
-        let addr = (upper as u16) << 8 | lower as u16;
-
-        self.cpu.pc += 2;
-        addr
+    #[test]
+    fn test_zero_page_wrap() {
+        let mut nes = Nes::default();
+        nes.cpu.x = 0x02;
+        nes.bus.ram[0x0001] = 0x11;
+        run("LDA $ff,X", &mut nes);
+        assert_eq!(nes.cpu.a, 0x11);
+
+        // the pointer at $ff takes its upper byte from $00
+        nes.cpu.x = 0x00;
+        nes.bus.ram[0x00ff] = 0x80;
+        nes.bus.ram[0x0000] = 0x04;
+        nes.bus.ram[0x0480] = 0x22;
+        run("LDA ($ff,X)", &mut nes);
+        assert_eq!(nes.cpu.a, 0x22);
     }
 
-    pub fn absolute_indirect(&mut self) -> u16 {
-        let addr = self.absolute();
-        let lower = self.fetch_memory8(addr);
-        let upper = self.fetch_memory8(addr) + 1;
-        let addr = (upper as u16) << 8 | lower as u16;
-        addr as u16
+    #[test]
+    fn test_indirect_indexed_page_cross() {
+        let mut nes = Nes::default();
+        nes.bus.ram[0x0010..0x0012].copy_from_slice(&[0xf0, 0x02]);
+        nes.bus.ram[0x0310] = 0x33;
+        nes.cpu.y = 0x20;
+        assert_eq!(run("LDA ($10),Y", &mut nes), 6);
+        assert_eq!(nes.cpu.a, 0x33);
+        nes.cpu.y = 0x0f;
+        assert_eq!(run("STA ($10),Y", &mut nes), 6);
+        assert_eq!(nes.bus.ram[0x02ff], 0x33);
     }
 }
@@ -0,0 +1,489 @@
+use crate::addressing::AddressingMode;
+use crate::bus::Bus;
+use crate::cpu::Cpu6502;
+use crate::interrupt::{IRQ_VECTOR, NMI_VECTOR, RESET_VECTOR};
+#[cfg(test)]
+use crate::nes::Nes;
+use crate::opcode::OPCODES;
+use std::collections::HashMap;
+use std::fmt;
+
+// A small two-pass 6502 assembler for writing CPU tests.
+//
+//     reset:  LDX #$00        ; labels end with a colon
+//     loop:   INX
+//             CPX #10
+//             BNE loop
+//             STA $0200,X
+//             .db $01, %10, 3
+//             .dw loop, $8000
+//
+// Hex numbers with one or two digits and decimal numbers below 256 pick the
+// zero page form of an instruction when it has one; labels always use the
+// absolute form. `<expr` and `>expr` take the low and high byte of a value.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    pub origin: u16,
+    pub bytes: Vec<u8>,
+    pub labels: HashMap<String, u16>,
+}
+
+#[derive(Debug, Clone)]
+enum Term {
+    Number(u16),
+    Label(String),
+}
+
+#[derive(Debug, Clone)]
+struct Expr {
+    terms: Vec<(bool, Term)>,
+    // Some(true) for the low byte `<`, Some(false) for the high byte `>`
+    select_low: Option<bool>,
+    byte: bool,
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+    None,
+    Accumulator,
+    Immediate(Expr),
+    Direct(Expr),
+    IndexedX(Expr),
+    IndexedY(Expr),
+    Indirect(Expr),
+    IndirectX(Expr),
+    IndirectY(Expr),
+}
+
+#[derive(Debug)]
+enum Statement {
+    Instruction {
+        opcode: u8,
+        mode: AddressingMode,
+        expr: Option<Expr>,
+    },
+    Bytes(Vec<Expr>),
+    Words(Vec<Expr>),
+}
+
+impl Statement {
+    fn len(&self) -> usize {
+        match self {
+            Statement::Instruction { mode, .. } => mode.bytes() as usize,
+            Statement::Bytes(exprs) => exprs.len(),
+            Statement::Words(exprs) => exprs.len() * 2,
+        }
+    }
+}
+
+pub fn assemble(source: &str, origin: u16) -> Result<Program, AsmError> {
+    let mut statements = Vec::new();
+    let mut labels = HashMap::new();
+    let mut addr = origin as usize;
+
+    for (index, line) in source.lines().enumerate() {
+        let error = |message: String| AsmError {
+            line: index + 1,
+            message,
+        };
+        let mut text = line.split(';').next().unwrap_or("").trim();
+
+        if let Some(colon) = text.find(':') {
+            let label = text[..colon].trim();
+            if !is_identifier(label) {
+                return Err(error(format!("invalid label `{}`", label)));
+            }
+            if labels.insert(label.to_string(), addr as u16).is_some() {
+                return Err(error(format!("duplicate label `{}`", label)));
+            }
+            text = text[colon + 1..].trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+
+        let (keyword, rest) = match text.find(char::is_whitespace) {
+            Some(space) => (&text[..space], text[space..].trim()),
+            None => (text, ""),
+        };
+        let statement = match keyword.to_ascii_lowercase().as_str() {
+            ".db" | ".byte" => Statement::Bytes(parse_list(rest).map_err(error)?),
+            ".dw" | ".word" => Statement::Words(parse_list(rest).map_err(error)?),
+            _ => {
+                let mnemonic = keyword.to_ascii_uppercase();
+                let operand = parse_operand(rest).map_err(error)?;
+                let (opcode, mode, expr) = select_opcode(&mnemonic, operand).map_err(error)?;
+                Statement::Instruction { opcode, mode, expr }
+            }
+        };
+        addr += statement.len();
+        if addr > 0x10000 {
+            return Err(error("program does not fit in memory".to_string()));
+        }
+        statements.push((index + 1, statement));
+    }
+
+    let mut bytes = Vec::new();
+    for (line, statement) in statements {
+        let error = |message: String| AsmError { line, message };
+        let pc = origin.wrapping_add(bytes.len() as u16);
+        match statement {
+            Statement::Instruction { opcode, mode, expr } => {
+                bytes.push(opcode);
+                let value = match &expr {
+                    Some(expr) => evaluate(expr, &labels).map_err(error)?,
+                    None => 0,
+                };
+                match mode {
+                    AddressingMode::Implied | AddressingMode::Accumulator => (),
+                    AddressingMode::Relative => {
+                        let offset = value as i32 - (pc as i32 + 2);
+                        if !(-128..=127).contains(&offset) {
+                            return Err(error(format!(
+                                "branch target ${:04X} out of range",
+                                value
+                            )));
+                        }
+                        bytes.push(offset as u8)
+                    }
+                    _ if mode.bytes() == 2 => {
+                        if value > 0xff {
+                            return Err(error(format!(
+                                "operand ${:04X} does not fit in a byte",
+                                value
+                            )));
+                        }
+                        bytes.push(value as u8)
+                    }
+                    _ => bytes.extend_from_slice(&value.to_le_bytes()),
+                }
+            }
+            Statement::Bytes(exprs) => {
+                for expr in exprs {
+                    let value = evaluate(&expr, &labels).map_err(error)?;
+                    if value > 0xff {
+                        return Err(error(format!("${:04X} does not fit in a byte", value)));
+                    }
+                    bytes.push(value as u8)
+                }
+            }
+            Statement::Words(exprs) => {
+                for expr in exprs {
+                    let value = evaluate(&expr, &labels).map_err(error)?;
+                    bytes.extend_from_slice(&value.to_le_bytes())
+                }
+            }
+        }
+    }
+
+    Ok(Program {
+        origin,
+        bytes,
+        labels,
+    })
+}
+
+// Picks the addressing mode for what the operand looks like, falling back to
+// the absolute forms when an instruction has no zero page variant.
+fn select_opcode(
+    mnemonic: &str,
+    operand: Operand,
+) -> Result<(u8, AddressingMode, Option<Expr>), String> {
+    let (candidates, expr): (&[AddressingMode], Option<Expr>) = match operand {
+        Operand::None => (
+            &[AddressingMode::Implied, AddressingMode::Accumulator],
+            None,
+        ),
+        Operand::Accumulator => (&[AddressingMode::Accumulator], None),
+        Operand::Immediate(expr) => (&[AddressingMode::Immediate], Some(expr)),
+        Operand::Direct(expr) if expr.byte => (
+            &[
+                AddressingMode::Relative,
+                AddressingMode::ZeroPage,
+                AddressingMode::Absolute,
+            ],
+            Some(expr),
+        ),
+        Operand::Direct(expr) => (
+            &[AddressingMode::Relative, AddressingMode::Absolute],
+            Some(expr),
+        ),
+        Operand::IndexedX(expr) if expr.byte => (
+            &[AddressingMode::ZeroPageX, AddressingMode::AbsoluteX],
+            Some(expr),
+        ),
+        Operand::IndexedX(expr) => (&[AddressingMode::AbsoluteX], Some(expr)),
+        Operand::IndexedY(expr) if expr.byte => (
+            &[AddressingMode::ZeroPageY, AddressingMode::AbsoluteY],
+            Some(expr),
+        ),
+        Operand::IndexedY(expr) => (&[AddressingMode::AbsoluteY], Some(expr)),
+        Operand::Indirect(expr) => (&[AddressingMode::Indirect], Some(expr)),
+        Operand::IndirectX(expr) => (&[AddressingMode::IndirectX], Some(expr)),
+        Operand::IndirectY(expr) => (&[AddressingMode::IndirectY], Some(expr)),
+    };
+
+    let mut known = false;
+    for mode in candidates {
+        let mut found = None;
+        for (code, opcode) in OPCODES.iter().enumerate() {
+            let opcode = match opcode {
+                Some(opcode) if opcode.instruction.mnemonic() == mnemonic => opcode,
+                _ => continue,
+            };
+            known = true;
+            if opcode.mode == *mode && (found.is_none() || opcode.official) {
+                found = Some(code as u8);
+                if opcode.official {
+                    break;
+                }
+            }
+        }
+        if let Some(code) = found {
+            return Ok((code, *mode, expr));
+        }
+    }
+    if known {
+        Err(format!(
+            "{} does not support this addressing mode",
+            mnemonic
+        ))
+    } else {
+        Err(format!("unknown mnemonic `{}`", mnemonic))
+    }
+}
+
+fn parse_operand(text: &str) -> Result<Operand, String> {
+    let text: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    let upper = text.to_ascii_uppercase();
+    if text.is_empty() {
+        return Ok(Operand::None);
+    }
+    if upper == "A" {
+        return Ok(Operand::Accumulator);
+    }
+    if let Some(rest) = text.strip_prefix('#') {
+        return Ok(Operand::Immediate(parse_expr(rest)?));
+    }
+    if let Some(inner) = text.strip_prefix('(') {
+        if upper.ends_with(",X)") {
+            return Ok(Operand::IndirectX(parse_expr(&inner[..inner.len() - 3])?));
+        }
+        if upper.ends_with("),Y") {
+            return Ok(Operand::IndirectY(parse_expr(&inner[..inner.len() - 3])?));
+        }
+        if let Some(inner) = inner.strip_suffix(')') {
+            return Ok(Operand::Indirect(parse_expr(inner)?));
+        }
+        return Err(format!("invalid operand `{}`", text));
+    }
+    if upper.ends_with(",X") {
+        return Ok(Operand::IndexedX(parse_expr(&text[..text.len() - 2])?));
+    }
+    if upper.ends_with(",Y") {
+        return Ok(Operand::IndexedY(parse_expr(&text[..text.len() - 2])?));
+    }
+    Ok(Operand::Direct(parse_expr(&text)?))
+}
+
+fn parse_list(text: &str) -> Result<Vec<Expr>, String> {
+    text.split(',')
+        .map(|item| parse_expr(item.trim()))
+        .collect()
+}
+
+fn parse_expr(text: &str) -> Result<Expr, String> {
+    let (select_low, mut rest) = match text.chars().next() {
+        Some('<') => (Some(true), &text[1..]),
+        Some('>') => (Some(false), &text[1..]),
+        _ => (None, text),
+    };
+    let mut terms = Vec::new();
+    let mut byte = true;
+    let mut positive = true;
+    loop {
+        // a sign in front of the first term is not an operator
+        let first = rest.chars().next().map_or(0, char::len_utf8);
+        let end = rest[first..]
+            .find(['+', '-'])
+            .map(|i| i + first)
+            .unwrap_or_else(|| rest.len());
+        let (term, term_byte) = parse_term(&rest[..end])?;
+        byte &= term_byte;
+        terms.push((positive, term));
+        if end == rest.len() {
+            break;
+        }
+        positive = &rest[end..=end] == "+";
+        rest = &rest[end + 1..];
+    }
+    Ok(Expr {
+        terms,
+        byte: byte || select_low.is_some(),
+        select_low,
+    })
+}
+
+// Returns the term and whether it is written as a byte-sized number.
+fn parse_term(text: &str) -> Result<(Term, bool), String> {
+    let invalid = || format!("invalid number `{}`", text);
+    if let Some(hex) = text.strip_prefix('$') {
+        let value = u16::from_str_radix(hex, 16).map_err(|_| invalid())?;
+        Ok((Term::Number(value), hex.len() <= 2))
+    } else if let Some(binary) = text.strip_prefix('%') {
+        let value = u16::from_str_radix(binary, 2).map_err(|_| invalid())?;
+        Ok((Term::Number(value), binary.len() <= 8))
+    } else if text.starts_with(|c: char| c.is_ascii_digit()) {
+        let value: u16 = text.parse().map_err(|_| invalid())?;
+        Ok((Term::Number(value), value <= 0xff))
+    } else if is_identifier(text) {
+        Ok((Term::Label(text.to_string()), false))
+    } else {
+        Err(format!("invalid expression `{}`", text))
+    }
+}
+
+fn evaluate(expr: &Expr, labels: &HashMap<String, u16>) -> Result<u16, String> {
+    let mut value: u16 = 0;
+    for (positive, term) in &expr.terms {
+        let term = match term {
+            Term::Number(number) => *number,
+            Term::Label(label) => *labels
+                .get(label)
+                .ok_or_else(|| format!("undefined label `{}`", label))?,
+        };
+        value = if *positive {
+            value.wrapping_add(term)
+        } else {
+            value.wrapping_sub(term)
+        };
+    }
+    Ok(match expr.select_low {
+        Some(true) => value & 0xff,
+        Some(false) => value >> 8,
+        None => value,
+    })
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl<B: Bus> Cpu6502<B> {
+    // Copies an assembled program into memory and resets the CPU into it.
+    // The reset vector points at the `reset` label, or at the start of the
+    // program without one; `nmi` and `irq` labels set the other vectors.
+    pub fn load_program(&mut self, program: &Program) {
+        for (index, byte) in program.bytes.iter().enumerate() {
+            self.bus
+                .poke(program.origin.wrapping_add(index as u16), *byte);
+        }
+
+        let reset = program.labels.get("reset").unwrap_or(&program.origin);
+        self.set_vector(RESET_VECTOR, *reset);
+        if let Some(nmi) = program.labels.get("nmi") {
+            self.set_vector(NMI_VECTOR, *nmi);
+        }
+        if let Some(irq) = program.labels.get("irq") {
+            self.set_vector(IRQ_VECTOR, *irq);
+        }
+
+        self.initialize();
+        self.reset();
+    }
+
+    fn set_vector(&mut self, vector: u16, addr: u16) {
+        self.bus.poke(vector, addr as u8);
+        self.bus.poke(vector + 1, (addr >> 8) as u8);
+    }
+}
+
+// Assembles `source` at $8000 and runs it until it reaches the `done` label.
+#[cfg(test)]
+pub(crate) fn execute(source: &str) -> Nes {
+    execute_with(source, |_| ())
+}
+
+// Like `execute`, but hands the loaded machine to `setup` before running it.
+#[cfg(test)]
+pub(crate) fn execute_with(source: &str, setup: impl FnOnce(&mut Nes)) -> Nes {
+    let program = assemble(source, 0x8000).unwrap();
+    let done = program.labels["done"];
+    let mut nes = Nes::default();
+    nes.load_program(&program);
+    setup(&mut nes);
+    for _ in 0..1000 {
+        if nes.cpu.pc == done {
+            return nes;
+        }
+        nes.step().unwrap();
+    }
+    panic!("program did not reach `done`");
+}
+
+#[cfg(test)]
+mod test {
+    use super::assemble;
+
+    #[test]
+    fn test_assemble_modes() {
+        let program = assemble(
+            "
+            start:  LDA #$10
+                    STA $20
+                    STA $0200,X
+                    LDA ($40),Y
+                    LDA ($40,X)
+                    LDX $30,Y
+                    LDA $30,Y      ; no zero page,Y form for LDA
+                    ASL
+                    ROR A
+                    JMP ($FFFC)
+                    BNE start
+                    .dw start
+                    .db <start, >start, %101
+            ",
+            0x8000,
+        )
+        .unwrap();
+        assert_eq!(
+            program.bytes,
+            vec![
+                0xa9, 0x10, 0x85, 0x20, 0x9d, 0x00, 0x02, 0xb1, 0x40, 0xa1, 0x40, 0xb6, 0x30, 0xb9,
+                0x30, 0x00, 0x0a, 0x6a, 0x6c, 0xfc, 0xff, 0xd0, 0xe9, 0x00, 0x80, 0x00, 0x80, 0x05,
+            ]
+        );
+        assert_eq!(program.labels["start"], 0x8000);
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        assert_eq!(
+            assemble("LDA", 0).unwrap_err().message,
+            "LDA does not support this addressing mode"
+        );
+        assert_eq!(assemble("\nFOO #1", 0).unwrap_err().line, 2);
+        assert!(assemble(".db 0\nBNE far\n.db 0\nfar: .db $100", 0).is_err());
+        assert!(assemble("JMP nowhere", 0).is_err());
+        for source in ["LDA #é", "LDA é+1", ".db é", "é: NOP", "LDA (é),Y"].iter() {
+            assert!(assemble(source, 0).is_err(), "{}", source);
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use crate::cpu::{Cpu, Cpu6502};
+use crate::error::CpuError;
+use crate::interrupt::Interrupt;
+use crate::ram::BusAccess;
+
+// Everything the CPU core sees of the machine around it. The NES memory map
+// is one implementation, `FlatBus` with plain 64K of RAM another.
+pub trait Bus {
+    fn read(&mut self, addr: u16) -> u8;
+
+    fn write(&mut self, addr: u16, value: u8);
+
+    // Reads without side effects, for traces and the disassembler.
+    fn peek(&self, addr: u16) -> u8;
+
+    // Stores without side effects, also into memory the CPU cannot write
+    // such as ROM, for loading programs.
+    fn poke(&mut self, addr: u16, value: u8) {
+        self.write(addr, value)
+    }
+
+    // Called once for every CPU cycle.
+    fn tick(&mut self) {}
+
+    // Called before every CPU read of `addr`. A DMA unit that wants the bus
+    // returns the access it made for each cycle it keeps the CPU halted on
+    // that read.
+    fn dma_cycle(&mut self, _addr: u16) -> Option<BusAccess> {
+        None
+    }
+
+    fn nmi_line(&self) -> bool {
+        false
+    }
+
+    fn irq_line(&self) -> bool {
+        false
+    }
+}
+
+// 64K of RAM and nothing else, for running plain 6502 programs.
+pub struct FlatBus {
+    pub ram: [u8; 0x10000],
+    // Test programs like Klaus Dormann's interrupt test raise interrupts by
+    // writing to a feedback register: bit 0 drives IRQ and bit 1 NMI.
+    pub feedback: Option<u16>,
+}
+
+impl Default for FlatBus {
+    fn default() -> Self {
+        FlatBus {
+            ram: [0; 0x10000],
+            feedback: None,
+        }
+    }
+}
+
+// How a run of a plain 6502 program ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    // the success address was reached
+    Success,
+    // the program got stuck in an instruction that jumps to itself
+    Trapped(u16),
+}
+
+impl FlatBus {
+    fn feedback(&self, bit: u8) -> bool {
+        match self.feedback {
+            Some(addr) => self.ram[addr as usize] & bit != 0,
+            None => false,
+        }
+    }
+}
+
+impl Bus for FlatBus {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        self.ram[addr as usize] = value
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+
+    fn nmi_line(&self) -> bool {
+        self.feedback(0x02)
+    }
+
+    fn irq_line(&self) -> bool {
+        self.feedback(0x01)
+    }
+}
+
+impl Cpu6502<FlatBus> {
+    // Copies a raw binary to `addr` and points PC at `pc` without going
+    // through the reset vector.
+    pub fn load_binary(&mut self, binary: &[u8], addr: u16, pc: u16) {
+        for (index, byte) in binary.iter().enumerate() {
+            self.bus.ram[addr.wrapping_add(index as u16) as usize] = *byte;
+        }
+        self.cpu = Cpu {
+            pc,
+            ..Cpu::default()
+        };
+        self.interrupt = Interrupt::default();
+        self.sequence = None;
+    }
+
+    // Runs until PC reaches `success` or an instruction jumps to itself,
+    // which is how test suites like Klaus Dormann's report a failure.
+    pub fn run_until_trap(&mut self, success: Option<u16>) -> Result<Outcome, CpuError> {
+        loop {
+            if Some(self.cpu.pc) == success {
+                return Ok(Outcome::Success);
+            }
+            let info = self.step()?;
+            if info.opcode.is_some() && self.cpu.pc == info.pc {
+                return Ok(Outcome::Trapped(info.pc));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FlatBus, Outcome};
+    use crate::asm::assemble;
+    use crate::cpu::Cpu6502;
+
+    #[test]
+    fn test_flat_bus() {
+        let program = assemble(
+            "
+                    LDA #$42
+                    STA $c000
+                    INC $c000
+            done:   JMP done
+            ",
+            0x0400,
+        )
+        .unwrap();
+        let mut cpu: Cpu6502<FlatBus> = Cpu6502::default();
+        cpu.load_program(&program);
+        while cpu.cpu.pc != program.labels["done"] {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.bus.ram[0xc000], 0x43);
+        assert_eq!(cpu.cpu.cycles, 7 + 2 + 4 + 6);
+    }
+
+    #[test]
+    fn test_run_until_trap() {
+        let program = assemble(
+            "
+                    LDX #$03
+            loop:   DEX
+                    BNE loop
+                    CPX #$00
+            fail:   BNE fail
+            pass:   JMP pass
+            ",
+            0x0200,
+        )
+        .unwrap();
+        let mut cpu: Cpu6502<FlatBus> = Cpu6502::default();
+        cpu.load_binary(&program.bytes, 0x0200, 0x0200);
+        assert_eq!(
+            cpu.run_until_trap(None),
+            Ok(Outcome::Trapped(program.labels["pass"]))
+        );
+
+        cpu.load_binary(&program.bytes, 0x0200, 0x0200);
+        let pass = program.labels["pass"];
+        assert_eq!(cpu.run_until_trap(Some(pass)), Ok(Outcome::Success));
+        assert_eq!(cpu.cpu.pc, pass);
+    }
+}
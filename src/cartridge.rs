@@ -0,0 +1,51 @@
+// The cartridge side of the CPU bus: expansion space at $4020-$5FFF, PRG-RAM
+// at $6000-$7FFF and PRG-ROM from $8000. Only NROM is supported, so ROM is
+// fixed and a 16K image shows up twice.
+pub struct Cartridge {
+    pub prg_rom: Vec<u8>,
+    pub prg_ram: [u8; 0x2000],
+}
+
+impl Default for Cartridge {
+    fn default() -> Self {
+        Cartridge {
+            prg_rom: Vec::new(),
+            prg_ram: [0; 0x2000],
+        }
+    }
+}
+
+impl Cartridge {
+    // `None` when nothing answers, as in expansion space or without a ROM.
+    pub fn read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7fff => Some(self.prg_ram[addr as usize - 0x6000]),
+            0x8000..=0xffff if !self.prg_rom.is_empty() => {
+                Some(self.prg_rom[(addr as usize - 0x8000) % self.prg_rom.len()])
+            }
+            _ => None,
+        }
+    }
+
+    // Writes to ROM are dropped; NROM has no registers to take them.
+    pub fn write(&mut self, addr: u16, value: u8) {
+        if let 0x6000..=0x7fff = addr {
+            self.prg_ram[addr as usize - 0x6000] = value
+        }
+    }
+
+    // Stores into ROM as well, for loading programs without an iNES image.
+    // An empty cartridge gets 32K of ROM.
+    pub fn poke(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x8000..=0xffff => {
+                if self.prg_rom.is_empty() {
+                    self.prg_rom = vec![0; 0x8000]
+                }
+                let len = self.prg_rom.len();
+                self.prg_rom[(addr as usize - 0x8000) % len] = value
+            }
+            _ => self.write(addr, value),
+        }
+    }
+}
@@ -0,0 +1,39 @@
+pub const CONTROLLER1: u16 = 0x4016;
+pub const CONTROLLER2: u16 = 0x4017;
+
+// A standard controller on $4016 or $4017. While the strobe bit is set the
+// shift register keeps reloading the buttons; once it is cleared every read
+// shifts out one button, A first, and reads past the eighth return 1.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Controller {
+    // A, B, Select, Start, Up, Down, Left, Right from bit 0 up
+    pub buttons: u8,
+    shift: u8,
+    strobe: bool,
+}
+
+impl Controller {
+    pub fn write(&mut self, value: u8) {
+        self.strobe = value & 0x01 != 0;
+        if self.strobe {
+            self.shift = self.buttons
+        }
+    }
+
+    pub fn read(&mut self) -> u8 {
+        if self.strobe {
+            return self.buttons & 0x01;
+        }
+        let bit = self.shift & 0x01;
+        self.shift = self.shift >> 1 | 0x80;
+        bit
+    }
+
+    pub fn peek(&self) -> u8 {
+        if self.strobe {
+            self.buttons & 0x01
+        } else {
+            self.shift & 0x01
+        }
+    }
+}
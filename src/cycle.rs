@@ -0,0 +1,523 @@
+use crate::addressing::AddressingMode;
+use crate::bus::Bus;
+use crate::cpu::{Cpu6502, StepInfo, STACK_PAGE};
+use crate::error::CpuError;
+use crate::instruction::{Instruction, UnstableOpcodes};
+use crate::interrupt::IRQ_VECTOR;
+use crate::opcode::{decode, Access, Opcode};
+
+// What the CPU is working through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Work {
+    Instruction(Opcode),
+    // an NMI or IRQ jumping through the vector
+    Interrupt(u16),
+}
+
+// The state an instruction carries from one cycle to the next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sequence {
+    pub work: Work,
+    // address of the instruction, or of the one the interrupt preempted
+    pub pc: u16,
+    // the cycle about to run, counting the opcode fetch as 0
+    pub t: u8,
+    // effective address, or the target of a jump or branch
+    pub addr: u16,
+    pub value: u8,
+    pub page_crossed: bool,
+    // the cycle the operand access starts at, once the address is known
+    pub operand: Option<u8>,
+    // CPU cycle count when the sequence started, to count DMA stalls in too
+    pub start: u64,
+    // the interrupt polled at the end of the previous cycle
+    pub poll: Option<u16>,
+}
+
+impl Sequence {
+    fn new(work: Work, pc: u16, start: u64) -> Self {
+        Sequence {
+            work,
+            pc,
+            t: 0,
+            addr: 0,
+            value: 0,
+            page_crossed: false,
+            operand: None,
+            start,
+            poll: None,
+        }
+    }
+}
+
+impl<B: Bus> Cpu6502<B> {
+    // Runs one CPU cycle, which is exactly one bus read or write. Returns the
+    // instruction or interrupt when this cycle finished it. A read that DMA
+    // halts the CPU on waits inside the cycle, so such a tick takes longer.
+    pub fn tick(&mut self) -> Result<Option<StepInfo>, CpuError> {
+        if let Some(error) = &self.cpu.halted {
+            return Err(error.clone());
+        }
+
+        let mut sequence = match self.sequence.take() {
+            Some(sequence) => sequence,
+            None => self.begin()?,
+        };
+        let done = match sequence.work {
+            Work::Instruction(opcode) if sequence.t > 0 => {
+                self.instruction_cycle(&mut sequence, opcode)
+            }
+            Work::Instruction(_) => false,
+            Work::Interrupt(vector) => self.interrupt_cycle(&mut sequence, vector, false),
+        };
+        sequence.t += 1;
+        self.advance_cycles(1);
+
+        if let Some(error) = &self.cpu.halted {
+            return Err(error.clone());
+        }
+        if done {
+            let opcode = match sequence.work {
+                Work::Instruction(opcode) => Some(opcode),
+                Work::Interrupt(_) => None,
+            };
+            // the first instruction of a handler always runs before the next interrupt
+            self.latch_interrupt(match opcode {
+                Some(opcode) if opcode.instruction != Instruction::BRK => sequence.poll,
+                _ => None,
+            });
+            return Ok(Some(StepInfo {
+                pc: sequence.pc,
+                opcode,
+                cycles: (self.cpu.cycles - sequence.start) as u16,
+            }));
+        }
+        sequence.poll = self.poll_interrupts();
+        self.sequence = Some(sequence);
+        Ok(None)
+    }
+
+    // Starts the next instruction: services the interrupt the last one
+    // polled, or fetches and decodes an opcode. Both use up the cycle `tick`
+    // is running.
+    fn begin(&mut self) -> Result<Sequence, CpuError> {
+        let pc = self.cpu.pc;
+        if let Some(vector) = self.take_interrupt() {
+            return Ok(Sequence::new(Work::Interrupt(vector), pc, self.cpu.cycles));
+        }
+
+        let code = self.peek_memory8(pc);
+        let bytes = [
+            code,
+            self.peek_memory8(pc.wrapping_add(1)),
+            self.peek_memory8(pc.wrapping_add(2)),
+        ];
+        let opcode = decode(code).expect("the opcode table covers all 256 opcodes");
+        if opcode.instruction.is_unstable() && self.unstable_opcodes == UnstableOpcodes::Reject {
+            return self.halt(CpuError::UnstableOpcode { pc, bytes });
+        }
+
+        self.trace(pc);
+        let start = self.cpu.cycles;
+        self.fetch_opcode(pc);
+        self.cpu.pc = pc.wrapping_add(1);
+        Ok(Sequence::new(Work::Instruction(opcode), pc, start))
+    }
+
+    fn halt(&mut self, error: CpuError) -> Result<Sequence, CpuError> {
+        self.cpu.halted = Some(error.clone());
+        Err(error)
+    }
+
+    // Reads the byte at PC and moves past it.
+    pub(crate) fn fetch_pc(&mut self) -> u8 {
+        let value = self.fetch_memory8(self.cpu.pc);
+        self.cpu.pc = self.cpu.pc.wrapping_add(1);
+        value
+    }
+
+    // Reads the byte at PC without moving, as the CPU does on cycles where it
+    // has nothing else to put on the bus.
+    pub(crate) fn dummy_read(&mut self) {
+        self.fetch_memory8(self.cpu.pc);
+    }
+
+    // The stack pointer is only incremented on pulls after a dummy read of
+    // the current top of the stack.
+    fn dummy_read_stack(&mut self) {
+        self.fetch_memory8(STACK_PAGE | self.cpu.s as u16);
+    }
+
+    // Runs cycle `t` (1 or later) of `opcode` and returns whether it was the last one.
+    fn instruction_cycle(&mut self, s: &mut Sequence, opcode: Opcode) -> bool {
+        let instruction = opcode.instruction;
+        match instruction {
+            Instruction::BRK if s.t == 1 => {
+                // BRK skips a padding byte, so the pushed address is the opcode plus two
+                self.fetch_pc();
+                false
+            }
+            Instruction::BRK => self.interrupt_cycle(s, IRQ_VECTOR, true),
+            Instruction::RTI => match s.t {
+                1 => self.read_and_continue(),
+                2 => {
+                    self.dummy_read_stack();
+                    false
+                }
+                3 => {
+                    let value = self.pull8();
+                    self.plp(value);
+                    false
+                }
+                4 => {
+                    s.addr = self.pull8() as u16;
+                    false
+                }
+                _ => {
+                    self.cpu.pc = (self.pull8() as u16) << 8 | s.addr;
+                    true
+                }
+            },
+            Instruction::RTS => match s.t {
+                1 => self.read_and_continue(),
+                2 => {
+                    self.dummy_read_stack();
+                    false
+                }
+                3 => {
+                    s.addr = self.pull8() as u16;
+                    false
+                }
+                4 => {
+                    self.cpu.pc = (self.pull8() as u16) << 8 | s.addr;
+                    false
+                }
+                _ => {
+                    self.fetch_pc();
+                    true
+                }
+            },
+            Instruction::JSR => match s.t {
+                1 => {
+                    s.addr = self.fetch_pc() as u16;
+                    false
+                }
+                2 => {
+                    self.dummy_read_stack();
+                    false
+                }
+                // the pushed address is the last byte of JSR, which RTS skips
+                3 => {
+                    self.push8((self.cpu.pc >> 8) as u8);
+                    false
+                }
+                4 => {
+                    self.push8(self.cpu.pc as u8);
+                    false
+                }
+                _ => {
+                    let upper = self.fetch_memory8(self.cpu.pc);
+                    self.cpu.pc = (upper as u16) << 8 | s.addr;
+                    true
+                }
+            },
+            Instruction::PHA | Instruction::PHP => match s.t {
+                1 => self.read_and_continue(),
+                _ => {
+                    let value = match instruction {
+                        Instruction::PHA => self.cpu.a,
+                        _ => self.cpu.p.to_stack(true),
+                    };
+                    self.push8(value);
+                    true
+                }
+            },
+            Instruction::PLA | Instruction::PLP => match s.t {
+                1 => self.read_and_continue(),
+                2 => {
+                    self.dummy_read_stack();
+                    false
+                }
+                _ => {
+                    let value = self.pull8();
+                    match instruction {
+                        Instruction::PLA => self.pla(value),
+                        _ => self.plp(value),
+                    }
+                    true
+                }
+            },
+            Instruction::JMP => self.jump_cycle(s, opcode.mode),
+            _ => match opcode.mode {
+                AddressingMode::Implied => {
+                    self.dummy_read();
+                    self.implied_op(instruction);
+                    true
+                }
+                AddressingMode::Accumulator => {
+                    self.dummy_read();
+                    self.cpu.a = self.modify_op(instruction, self.cpu.a);
+                    true
+                }
+                AddressingMode::Immediate => {
+                    let value = self.fetch_pc();
+                    self.read_op(instruction, value);
+                    true
+                }
+                AddressingMode::Relative => self.branch_cycle(s, instruction),
+                _ => match s.operand {
+                    Some(start) => self.operand_cycle(s, opcode, s.t - start),
+                    None => self.address_cycle(s, opcode),
+                },
+            },
+        }
+    }
+
+    fn read_and_continue(&mut self) -> bool {
+        self.dummy_read();
+        false
+    }
+
+    fn jump_cycle(&mut self, s: &mut Sequence, mode: AddressingMode) -> bool {
+        match (mode, s.t) {
+            (_, 1) => {
+                s.addr = self.fetch_pc() as u16;
+                false
+            }
+            (AddressingMode::Absolute, _) => {
+                let upper = self.fetch_memory8(self.cpu.pc);
+                self.cpu.pc = (upper as u16) << 8 | s.addr;
+                true
+            }
+            (_, 2) => {
+                s.addr |= (self.fetch_pc() as u16) << 8;
+                false
+            }
+            (_, 3) => {
+                s.value = self.fetch_memory8(s.addr);
+                false
+            }
+            // JMP ($xxff) fetches the upper byte from $xx00 instead of the next
+            // page, because the CPU never carries into the upper byte of the pointer.
+            _ => {
+                let pointer = s.addr & 0xff00 | (s.addr.wrapping_add(1) & 0x00ff);
+                let upper = self.fetch_memory8(pointer);
+                self.cpu.pc = (upper as u16) << 8 | s.value as u16;
+                true
+            }
+        }
+    }
+
+    // A taken branch spends one more cycle adding the offset to the lower byte
+    // of PC, and another one fixing up the upper byte if that crossed a page.
+    fn branch_cycle(&mut self, s: &mut Sequence, instruction: Instruction) -> bool {
+        match s.t {
+            1 => {
+                let delta = self.fetch_pc();
+                s.addr = self.cpu.pc.wrapping_add(delta as i8 as u16);
+                s.page_crossed = s.addr & 0xff00 != self.cpu.pc & 0xff00;
+                !self.condition(instruction)
+            }
+            2 => {
+                self.dummy_read();
+                self.cpu.pc = self.cpu.pc & 0xff00 | s.addr & 0x00ff;
+                if !s.page_crossed {
+                    self.cpu.pc = s.addr
+                }
+                !s.page_crossed
+            }
+            _ => {
+                self.dummy_read();
+                self.cpu.pc = s.addr;
+                true
+            }
+        }
+    }
+
+    // Cycle `step` of accessing the operand at the effective address.
+    fn operand_cycle(&mut self, s: &mut Sequence, opcode: Opcode, step: u8) -> bool {
+        let instruction = opcode.instruction;
+        match (opcode.access, step) {
+            (Access::Write, _) => {
+                let (addr, value) = self.store_op(instruction, s.addr, s.page_crossed);
+                self.set_memory8(addr, value);
+                true
+            }
+            (Access::ReadModifyWrite, 0) => {
+                s.value = self.fetch_memory8(s.addr);
+                false
+            }
+            // the unmodified value is written back while the ALU works on it
+            (Access::ReadModifyWrite, 1) => {
+                self.set_memory8(s.addr, s.value);
+                s.value = self.modify_op(instruction, s.value);
+                false
+            }
+            (Access::ReadModifyWrite, _) => {
+                self.set_memory8(s.addr, s.value);
+                true
+            }
+            _ => {
+                let value = self.fetch_memory8(s.addr);
+                self.read_op(instruction, value);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::addressing::AddressingMode;
+    use crate::asm::assemble;
+    use crate::bus::FlatBus;
+    use crate::cpu::Cpu6502;
+    use crate::flag::Status;
+    use crate::instruction::Instruction;
+    use crate::interrupt::IrqSource;
+    use crate::nes::Nes;
+    use crate::opcode::OPCODES;
+    use crate::ram::BusOp;
+
+    // Runs one instruction at $0200 and returns the bus accesses it made.
+    fn accesses(cpu: &mut Cpu6502<FlatBus>, bytes: &[u8]) -> Vec<(u16, BusOp)> {
+        cpu.bus.ram[0x0200..0x0200 + bytes.len()].copy_from_slice(bytes);
+        cpu.cpu.pc = 0x0200;
+        cpu.bus_log = Some(Vec::new());
+        cpu.step().unwrap();
+        let log = cpu.bus_log.take().unwrap();
+        log.iter().map(|access| (access.addr, access.op)).collect()
+    }
+
+    #[test]
+    fn test_cycle_counts() {
+        assert!(OPCODES.iter().all(Option::is_some));
+        for (code, opcode) in OPCODES.iter().enumerate() {
+            let opcode = match opcode {
+                Some(opcode) if opcode.instruction != Instruction::KIL => opcode,
+                _ => continue,
+            };
+            let mut cpu: Cpu6502<FlatBus> = Cpu6502::default();
+            // operands of $10 never cross a page, and all flags clear makes
+            // BCC, BNE, BPL and BVC the taken branches
+            cpu.cpu.p = Status::from_bits(0);
+            cpu.cpu.pc = 0x0200;
+            cpu.bus.ram[0x0200..0x0203].copy_from_slice(&[code as u8, 0x10, 0x10]);
+
+            let mut ticks = 0;
+            while cpu.tick().unwrap().is_none() {
+                ticks += 1;
+            }
+            let mut expected = opcode.cycles as u16;
+            if opcode.mode == AddressingMode::Relative && cpu.cpu.pc == 0x0212 {
+                expected += 1;
+            }
+            assert_eq!(ticks + 1, expected, "opcode {:02X}", code);
+        }
+    }
+
+    #[test]
+    fn test_bus_cycles() {
+        let mut cpu: Cpu6502<FlatBus> = Cpu6502::default();
+        use BusOp::*;
+
+        // INC $10,X reads the base, then writes the old value back before the new one
+        cpu.cpu.x = 0x01;
+        assert_eq!(
+            accesses(&mut cpu, &[0xf6, 0x10]),
+            vec![
+                (0x0200, Read),
+                (0x0201, Read),
+                (0x0010, Read),
+                (0x0011, Read),
+                (0x0011, Write),
+                (0x0011, Write)
+            ]
+        );
+
+        // LDA $02ff,X reads from the wrong page before the fix-up
+        assert_eq!(
+            accesses(&mut cpu, &[0xbd, 0xff, 0x02]),
+            vec![
+                (0x0200, Read),
+                (0x0201, Read),
+                (0x0202, Read),
+                (0x0200, Read),
+                (0x0300, Read)
+            ]
+        );
+
+        // a taken BNE across a page boundary reads at PC with the upper byte unfixed
+        cpu.cpu.p = Status::from_bits(0);
+        assert_eq!(
+            accesses(&mut cpu, &[0xd0, 0x80]),
+            vec![
+                (0x0200, Read),
+                (0x0201, Read),
+                (0x0202, Read),
+                (0x0282, Read)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tick_interleaves() {
+        let mut cpu: Cpu6502<FlatBus> = Cpu6502::default();
+        // LDA $10 then STA $11
+        cpu.bus.ram[0x0200..0x0204].copy_from_slice(&[0xa5, 0x10, 0x85, 0x11]);
+        cpu.bus.ram[0x0010] = 0x42;
+        cpu.cpu.pc = 0x0200;
+
+        assert_eq!(cpu.tick().unwrap(), None);
+        assert_eq!(cpu.tick().unwrap(), None);
+        assert_eq!(cpu.cpu.a, 0x00, "the operand is read on the last cycle");
+        let info = cpu.tick().unwrap().unwrap();
+        assert_eq!((info.pc, info.cycles), (0x0200, 3));
+        assert_eq!(cpu.cpu.a, 0x42);
+
+        // step finishes an instruction that was started with tick
+        assert_eq!(cpu.tick().unwrap(), None);
+        let info = cpu.step().unwrap();
+        assert_eq!((info.pc, info.cycles), (0x0202, 3));
+        assert_eq!(cpu.bus.ram[0x0011], 0x42);
+        assert_eq!(cpu.cpu.cycles, 6);
+    }
+
+    #[test]
+    fn test_interrupt_poll_delay() {
+        let program = assemble(
+            "
+            reset:  CLI
+                    INX
+                    SEI
+                    INY
+            irq:    RTI
+            ",
+            0x8000,
+        )
+        .unwrap();
+        let mut nes = Nes::default();
+        nes.load_program(&program);
+        nes.set_irq(IrqSource::Mapper, true);
+        let irq = program.labels["irq"];
+        let mnemonic = |nes: &mut Nes| nes.step().unwrap().opcode.map(|op| op.instruction);
+
+        // CLI clears I on its last cycle, after the poll, so INX still runs
+        assert_eq!(mnemonic(&mut nes), Some(Instruction::CLI));
+        assert_eq!(mnemonic(&mut nes), Some(Instruction::INX));
+        let info = nes.step().unwrap();
+        assert_eq!((info.opcode, info.pc, nes.cpu.pc), (None, 0x8002, irq));
+
+        // RTI restores I before its poll, so the IRQ comes right back
+        assert_eq!(mnemonic(&mut nes), Some(Instruction::RTI));
+        assert_eq!(nes.step().unwrap().opcode, None);
+        nes.set_irq(IrqSource::Mapper, false);
+        assert_eq!(mnemonic(&mut nes), Some(Instruction::RTI));
+
+        // SEI sets I too late to stop the IRQ it polled
+        nes.set_irq(IrqSource::Mapper, true);
+        assert_eq!(mnemonic(&mut nes), Some(Instruction::SEI));
+        let info = nes.step().unwrap();
+        assert_eq!((info.opcode, info.pc, nes.cpu.y), (None, 0x8003, 0x00));
+    }
+}
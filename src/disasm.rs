@@ -0,0 +1,153 @@
+use crate::addressing::AddressingMode;
+use crate::bus::Bus;
+use crate::cpu::Cpu6502;
+use crate::opcode::{decode, Opcode};
+use std::fmt;
+use std::ops::RangeInclusive;
+
+// One disassembled instruction, or a `.db` line for bytes that do not
+// decode to a complete instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub opcode: Option<Opcode>,
+    pub text: String,
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        write!(
+            f,
+            "${:04X}  {:<8}  {}",
+            self.addr,
+            bytes.join(" "),
+            self.text
+        )
+    }
+}
+
+// Decodes the instruction at `addr`. `bytes` holds the bytes from `addr`
+// onwards; only the first three are looked at.
+pub fn decode_line(addr: u16, bytes: &[u8]) -> Line {
+    let opcode = bytes.first().and_then(|code| decode(*code));
+    match opcode {
+        Some(opcode) if bytes.len() >= opcode.bytes as usize => {
+            let bytes = bytes[..opcode.bytes as usize].to_vec();
+            let operand = format_operand(opcode.mode, addr, &bytes[1..]);
+            let mut text = opcode.instruction.mnemonic();
+            if !operand.is_empty() {
+                text = format!("{} {}", text, operand);
+            }
+            Line {
+                addr,
+                bytes,
+                opcode: Some(opcode),
+                text,
+            }
+        }
+        _ => {
+            let bytes = bytes[..bytes.len().min(1)].to_vec();
+            let text = match bytes.first() {
+                Some(byte) => format!(".db ${:02X}", byte),
+                None => ".db".to_string(),
+            };
+            Line {
+                addr,
+                bytes,
+                opcode: None,
+                text,
+            }
+        }
+    }
+}
+
+// Formats the operand bytes following the opcode. Branch targets are
+// resolved relative to the instruction at `addr`.
+pub fn format_operand(mode: AddressingMode, addr: u16, operand: &[u8]) -> String {
+    let byte = || operand[0];
+    let word = || (operand[1] as u16) << 8 | operand[0] as u16;
+    match mode {
+        AddressingMode::Implied => String::new(),
+        AddressingMode::Accumulator => "A".to_string(),
+        AddressingMode::Immediate => format!("#${:02X}", byte()),
+        AddressingMode::ZeroPage => format!("${:02X}", byte()),
+        AddressingMode::ZeroPageX => format!("${:02X},X", byte()),
+        AddressingMode::ZeroPageY => format!("${:02X},Y", byte()),
+        AddressingMode::Absolute => format!("${:04X}", word()),
+        AddressingMode::AbsoluteX => format!("${:04X},X", word()),
+        AddressingMode::AbsoluteY => format!("${:04X},Y", word()),
+        AddressingMode::Relative => {
+            let target = addr.wrapping_add(2).wrapping_add(byte() as i8 as u16);
+            format!("${:04X}", target)
+        }
+        AddressingMode::Indirect => format!("(${:04X})", word()),
+        AddressingMode::IndirectX => format!("(${:02X},X)", byte()),
+        AddressingMode::IndirectY => format!("(${:02X}),Y", byte()),
+    }
+}
+
+// Disassembles a raw byte slice that is mapped at `base`.
+pub fn disassemble(bytes: &[u8], base: u16) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let line = decode_line(base.wrapping_add(offset as u16), &bytes[offset..]);
+        offset += line.bytes.len();
+        lines.push(line);
+    }
+    lines
+}
+
+impl<B: Bus> Cpu6502<B> {
+    // Disassembles CPU memory, starting an instruction at every address the
+    // previous one ends at until the range is exhausted.
+    pub fn disassemble(&self, range: RangeInclusive<u16>) -> Vec<Line> {
+        let mut lines = Vec::new();
+        let mut addr = *range.start() as u32;
+        while addr <= *range.end() as u32 {
+            let bytes: Vec<u8> = (0..3)
+                .map(|i| self.peek_memory8((addr as u16).wrapping_add(i)))
+                .collect();
+            let line = decode_line(addr as u16, &bytes);
+            addr += line.bytes.len() as u32;
+            lines.push(line);
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::disassemble;
+
+    #[test]
+    fn test_disassemble() {
+        let bytes = [
+            0xa9, 0x10, 0x9d, 0x00, 0x02, 0xd0, 0xf9, 0x6c, 0xfc, 0xff, 0xa7, 0x20,
+        ];
+        let lines: Vec<String> = disassemble(&bytes, 0x8004)
+            .iter()
+            .map(|line| line.to_string())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "$8004  A9 10     LDA #$10",
+                "$8006  9D 00 02  STA $0200,X",
+                "$8009  D0 F9     BNE $8004",
+                "$800B  6C FC FF  JMP ($FFFC)",
+                "$800E  A7 20     LAX $20",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disassemble_truncated() {
+        let lines = disassemble(&[0xad, 0x00], 0xc000);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].to_string(), "$C000  AD        .db $AD");
+        assert_eq!(lines[1].text, "BRK");
+    }
+}
@@ -0,0 +1,258 @@
+use crate::bus::Bus;
+use crate::controller::{CONTROLLER1, CONTROLLER2};
+use crate::nes::NesBus;
+use crate::ppu::OAM_DATA;
+use crate::ram::{BusAccess, BusOp};
+
+// A copy of one page to OAM, started by writing the page to $4014.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OamDma {
+    pub page: u8,
+    // bytes written to OAM so far
+    pub count: u16,
+    // the byte read on the last get cycle, waiting for a put cycle
+    pub value: Option<u8>,
+}
+
+// A DMC sample byte fetch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmcDma {
+    pub addr: u16,
+    // the DMC needs one cycle after the halt before it can read
+    pub ready: bool,
+}
+
+// The DMA units of the 2A03. Both halt the CPU on one of its reads and then
+// share the bus: DMA reads happen on get cycles and OAM writes on put
+// cycles, which alternate with every CPU cycle.
+#[derive(Debug, Default)]
+pub struct Dma {
+    pub oam: Option<OamDma>,
+    pub dmc: Option<DmcDma>,
+    pub halted: bool,
+    // whether the last cycle read the address the CPU is halted on
+    pub reread: bool,
+}
+
+impl NesBus {
+    pub fn start_oam_dma(&mut self, page: u8) {
+        self.dma.oam = Some(OamDma {
+            page,
+            count: 0,
+            value: None,
+        })
+    }
+
+    // Called by the DMC channel when its sample buffer runs empty. The byte
+    // ends up in `apu.dmc_sample`.
+    pub fn request_dmc_fetch(&mut self, addr: u16) {
+        self.dma.dmc = Some(DmcDma { addr, ready: false })
+    }
+
+    fn get_cycle(&self) -> bool {
+        self.cycles & 1 == 0
+    }
+
+    // Runs one cycle of the CPU being halted on a read of `addr` and returns
+    // the access made on the bus. Cycles the DMA does not use the bus on
+    // repeat that read, which is how DMC fetches lose controller bits and
+    // skip bytes of $2007. OAM DMA takes 513 cycles, or 514 when it has to
+    // wait for a get cycle, and every DMC fetch in between adds two more.
+    pub(crate) fn run_dma(&mut self, addr: u16) -> Option<BusAccess> {
+        if self.dma.oam.is_none() && self.dma.dmc.is_none() {
+            self.dma.halted = false;
+            return None;
+        }
+        if !self.dma.halted {
+            self.dma.halted = true;
+            self.dma.reread = true;
+            return Some(self.dma_read(addr));
+        }
+
+        let dmc_ready = self.dma.dmc.is_some_and(|dmc| dmc.ready);
+        let oam_value = self.dma.oam.and_then(|oam| oam.value);
+        let access = match (self.get_cycle(), self.dma.oam) {
+            (true, _) if dmc_ready => {
+                let dmc = self.dma.dmc.take().unwrap();
+                let access = self.dma_read(dmc.addr);
+                self.apu.dmc_sample = Some(access.value);
+                access
+            }
+            (true, Some(oam)) if oam_value.is_none() => {
+                let access = self.dma_read((oam.page as u16) << 8 | oam.count);
+                self.dma.oam = Some(OamDma {
+                    value: Some(access.value),
+                    ..oam
+                });
+                access
+            }
+            (false, Some(oam)) if oam_value.is_some() => {
+                let value = oam_value.unwrap();
+                self.write_register(OAM_DATA, value);
+                let count = oam.count + 1;
+                self.dma.oam = match count {
+                    0x100 => None,
+                    _ => Some(OamDma {
+                        count,
+                        value: None,
+                        ..oam
+                    }),
+                };
+                BusAccess {
+                    addr: OAM_DATA,
+                    value,
+                    op: BusOp::Write,
+                }
+            }
+            _ => self.dma_reread(addr),
+        };
+        self.dma.reread = access.addr == addr && access.op == BusOp::Read;
+        if let Some(dmc) = &mut self.dma.dmc {
+            dmc.ready = true
+        }
+        Some(access)
+    }
+
+    fn dma_read(&mut self, addr: u16) -> BusAccess {
+        BusAccess {
+            addr,
+            value: self.read(addr),
+            op: BusOp::Read,
+        }
+    }
+
+    // The controllers are only clocked once by back-to-back reads, because
+    // /OE stays asserted between them. Every other register sees each read.
+    fn dma_reread(&mut self, addr: u16) -> BusAccess {
+        match addr {
+            CONTROLLER1 | CONTROLLER2 if self.dma.reread => {
+                let value = self.peek(addr);
+                self.open_bus = value;
+                BusAccess {
+                    addr,
+                    value,
+                    op: BusOp::Read,
+                }
+            }
+            _ => self.dma_read(addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::asm::assemble;
+    use crate::nes::Nes;
+
+    // Runs `LDA #$02 / STA $4014 / NOP` with the bus clock shifted by `skew`
+    // cycles and returns how long the NOP took, which covers the DMA.
+    fn oam_dma(skew: u64, dmc: bool) -> (u16, Nes) {
+        let program = assemble("LDA #$02\nSTA $4014\nNOP", 0x8000).unwrap();
+        let mut nes = Nes::default();
+        nes.load_program(&program);
+        nes.bus.cycles += skew;
+        for i in 0..0x100 {
+            nes.bus.ram[0x0200 + i] = i as u8;
+        }
+        nes.bus.ppu.oam_addr = 0x10;
+        nes.step().unwrap();
+        nes.step().unwrap();
+        if dmc {
+            nes.bus.request_dmc_fetch(0x8000);
+        }
+        let cycles = nes.step().unwrap().cycles;
+        (cycles, nes)
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        let (even, nes) = oam_dma(0, false);
+        let (odd, _) = oam_dma(1, false);
+        let mut stalls = [even - 2, odd - 2];
+        stalls.sort();
+        assert_eq!(stalls, [513, 514]);
+
+        // the copy starts at OAMADDR and wraps around
+        assert_eq!(nes.bus.ppu.s_ram[0x10], 0x00);
+        assert_eq!(nes.bus.ppu.s_ram[0x0f], 0xff);
+        assert_eq!(nes.bus.ppu.oam_addr, 0x10);
+    }
+
+    #[test]
+    fn test_dmc_during_oam_dma() {
+        for skew in 0..2 {
+            let (alone, _) = oam_dma(skew, false);
+            let (shared, nes) = oam_dma(skew, true);
+            assert_eq!(shared, alone + 2);
+            assert_eq!(nes.bus.apu.dmc_sample, Some(0xa9));
+            assert_eq!(nes.bus.ppu.s_ram[0x10], 0x00);
+        }
+    }
+
+    // Runs `source` up to the absolute read at its `read` label and lets a
+    // DMC fetch halt the CPU on the read itself. The bus log covers the read.
+    fn dmc_on_read(source: &str, setup: impl FnOnce(&mut Nes)) -> Nes {
+        let program = assemble(source, 0x8000).unwrap();
+        let mut nes = Nes::default();
+        nes.load_program(&program);
+        setup(&mut nes);
+        while nes.cpu.pc != program.labels["read"] {
+            nes.step().unwrap();
+        }
+        for _ in 0..3 {
+            nes.tick().unwrap();
+        }
+        nes.bus.request_dmc_fetch(0x8000);
+        nes.bus_log = Some(Vec::new());
+        nes.step().unwrap();
+        assert_eq!(nes.bus.apu.dmc_sample, Some(0xa9));
+        nes
+    }
+
+    #[test]
+    fn test_dmc_dma_rereads_controller() {
+        let mut nes = dmc_on_read(
+            "
+                    LDA #$01
+                    STA $4016
+                    LSR A
+                    STA $4016
+            read:   LDA $4016
+            ",
+            |nes| nes.bus.controllers[0].buttons = 0x02,
+        );
+        assert_eq!(nes.cpu.a & 0x01, 0x01, "only the A button was lost");
+
+        // the halt clocks out A, the reread right after it sees B without
+        // clocking, and the CPU reads B again after the DMC fetch
+        let log: Vec<(u16, u8)> = nes
+            .bus_log
+            .take()
+            .unwrap()
+            .iter()
+            .map(|access| (access.addr, access.value & 0x01))
+            .collect();
+        assert_eq!(
+            log,
+            vec![(0x4016, 0), (0x4016, 1), (0x8000, 1), (0x4016, 1)]
+        );
+    }
+
+    #[test]
+    fn test_dmc_dma_rereads_ppudata() {
+        let nes = dmc_on_read(
+            "
+                    LDA #$20
+                    STA $2006
+                    LDA #$00
+                    STA $2006
+                    LDA $2007
+            read:   LDA $2007
+            ",
+            |nes| nes.bus.ppu.ram[0x2000..0x2005].copy_from_slice(&[0x11, 0x22, 0x33, 0x44, 0x55]),
+        );
+        // every halted read moves the VRAM address, so two bytes are skipped
+        assert_eq!(nes.cpu.a, 0x33);
+        assert_eq!(nes.bus.ppu.ptr, 0x2004);
+    }
+}
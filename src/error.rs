@@ -0,0 +1,27 @@
+use std::fmt;
+
+// Why the CPU halted. It stays halted with the same error until a reset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CpuError {
+    // a KIL opcode locked up the CPU
+    Jammed { pc: u16, opcode: u8 },
+    // an unstable opcode while `UnstableOpcodes::Reject` is selected
+    UnstableOpcode { pc: u16, bytes: [u8; 3] },
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuError::Jammed { pc, opcode } => {
+                write!(f, "CPU jammed by opcode {:02X} at ${:04X}", opcode, pc)
+            }
+            CpuError::UnstableOpcode { pc, bytes } => write!(
+                f,
+                "unstable opcode at ${:04X}: {:02X} {:02X} {:02X}",
+                pc, bytes[0], bytes[1], bytes[2]
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CpuError {}
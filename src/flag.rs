@@ -1,75 +1,118 @@
-use crate::instruction::Instruction;
+use crate::bus::Bus;
+use crate::cpu::Cpu6502;
 use crate::nes::Nes;
+use std::ops::BitOr;
+
+// Processor status register.
+//
+// 7 6 5 4 3 2 1 0
+// N V U B D I Z C
+//
+// B and U are not real flip-flops: they only exist in the copy of P pushed
+// to the stack. The register itself always reads with U set and B clear.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Status(u8);
+
+impl Status {
+    pub const C: Status = Status(0x01);
+    pub const Z: Status = Status(0x02);
+    pub const I: Status = Status(0x04);
+    pub const D: Status = Status(0x08);
+    pub const B: Status = Status(0x10);
+    pub const U: Status = Status(0x20);
+    pub const V: Status = Status(0x40);
+    pub const N: Status = Status(0x80);
+
+    // Loads P from a byte, as PLP and RTI do: B is dropped and U stays set.
+    pub const fn from_bits(bits: u8) -> Self {
+        Status(bits & !Status::B.0 | Status::U.0)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    // The byte pushed to the stack. B is set for PHP and BRK, and clear for
+    // NMI and IRQ; U is always set.
+    pub const fn to_stack(self, brk: bool) -> u8 {
+        if brk {
+            self.0 | Status::B.0 | Status::U.0
+        } else {
+            self.0 | Status::U.0
+        }
+    }
+
+    pub fn contains(self, flag: Status) -> bool {
+        self.0 & flag.0 == flag.0
+    }
+
+    pub fn set(&mut self, flag: Status, active: bool) {
+        if active {
+            self.0 |= flag.0
+        } else {
+            self.0 &= !flag.0
+        }
+        *self = Status::from_bits(self.0)
+    }
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Status::U | Status::I
+    }
+}
+
+impl BitOr for Status {
+    type Output = Status;
+
+    // Combined flags are a whole register value, so U is set and B dropped.
+    fn bitor(self, rhs: Status) -> Status {
+        Status::from_bits(self.0 | rhs.0)
+    }
+}
+
+impl std::fmt::Debug for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:02x}", self.0)
+    }
+}
 
 impl Nes {
     pub fn set_v_blank(&mut self) {
-        self.ram[0x2002] = self.ram[0x2002] | 0x80
+        self.bus.ppu.status |= 0x80;
+        self.set_nmi_line(self.bus.nmi_output())
     }
 
     // clearVBlank VBlankを解除
     pub fn clear_v_blank(&mut self) {
-        self.ram[0x2002] = self.ram[0x2002] & 0x7f
+        self.bus.ppu.status &= 0x7f;
+        self.set_nmi_line(self.bus.nmi_output())
     }
+}
 
+impl<B: Bus> Cpu6502<B> {
     pub fn flag_n(&mut self, b: u8) {
-        if b & 0x80 != 0 {
-            self.cpu.p |= 0x80
-        } else {
-            self.cpu.p &= 0x7f
-        }
+        self.cpu.p.set(Status::N, b & 0x80 != 0)
     }
 
+    // Signed overflow happens when both operands share a sign that the result lacks.
     pub fn flag_v(&mut self, b0: u8, b1: u8, b: u8) {
-        if ((b0 >> 7) ^ (b1 >> 7) != 0) && (b1 != b) {
-            self.cpu.p |= 0x40
-        } else {
-            self.cpu.p &= 0xbf
-        }
+        self.cpu.p.set(Status::V, (b0 ^ b) & (b1 ^ b) & 0x80 != 0)
     }
 
     pub fn flag_z(&mut self, b: u8) {
-        if b == 0 {
-            self.cpu.p |= 0x02
-        } else {
-            self.cpu.p &= 0xfd
-        }
+        self.cpu.p.set(Status::Z, b == 0)
     }
 
     pub fn flag_i(&mut self, active: bool) {
-        if active {
-            self.cpu.p |= 0x04;
-        } else {
-            self.cpu.p &= !0x04;
-        }
+        self.cpu.p.set(Status::I, active)
     }
 
-    pub fn flag_c(&mut self, instruction: Instruction, b: u16) {
-        if (b >> 8) != 0 {
-            if let Instruction::ADC = instruction {
-                self.set_c_flag()
-            } else {
-                self.clear_c_flag()
-            }
-        } else if let Instruction::ADC = instruction {
-            self.clear_c_flag()
-        } else {
-            self.set_c_flag()
-        }
+    pub fn flag_c(&mut self, carry: bool) {
+        self.cpu.p.set(Status::C, carry)
     }
 
     pub fn flag_d(&mut self, active: bool) {
-        if active {
-            self.cpu.p |= 0x08
-        } else {
-            self.cpu.p &= 0xfb
-        }
-    }
-
-    pub fn set_c_flag(&mut self) {
-        self.cpu.p |= 0x01
-    }
-
-    pub fn clear_c_flag(&mut self) {
-        self.cpu.p &= 0xfe
+        self.cpu.p.set(Status::D, active)
     }
 }
@@ -1,10 +1,9 @@
-use crate::nes::Nes;
+use crate::bus::Bus;
+use crate::cpu::{Cpu6502, Variant};
+use crate::error::CpuError;
+use crate::flag::Status;
 
-const PPU_ADDR: u16 = 0x2006;
-const PPU_DATA: u16 = 0x2007;
-const SPRITE_DMA: u16 = 0x4014;
-
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Instruction {
     ADC,
     SBC,
@@ -62,429 +61,378 @@ pub enum Instruction {
     BRK,
     BIT,
     NOP,
+    // unofficial
+    LAX,
+    SAX,
+    DCP,
+    ISC,
+    SLO,
+    RLA,
+    SRE,
+    RRA,
+    ANC,
+    ALR,
+    ARR,
+    AXS,
+    LAS,
+    KIL,
+    // unofficial and unstable on real hardware
+    XAA,
+    LXA,
+    AHX,
+    SHX,
+    SHY,
+    TAS,
 }
 
-impl Nes {
-    pub fn adc(&mut self, addr: u16) {
-        let c_flag = self.cpu.p & 0x01;
-        let a_flag = self.cpu.a;
-        let value = self.cpu.a + self.fetch_memory8(addr) + c_flag;
-        self.cpu.a = value;
-        self.flag_n(value);
-        self.flag_v(a_flag, value, value);
-        self.flag_z(value);
-        self.flag_c(Instruction::ADC, value as u16);
+impl Instruction {
+    pub fn mnemonic(self) -> String {
+        format!("{:?}", self)
     }
 
-    pub fn sbc(&mut self, addr: u16) {
-        let not_c_flag = !self.cpu.p & 0x01;
-        let a_flag = self.cpu.a;
-        let value = self.cpu.a - self.fetch_memory8(addr) - not_c_flag;
-        self.cpu.a = value;
-        self.flag_n(value);
-        self.flag_v(a_flag, value, value);
-        self.flag_z(value);
-        self.flag_c(Instruction::SBC, value as u16);
-    }
-
-    pub fn and(&mut self, addr: u16) {
-        let value = self.cpu.a & self.fetch_memory8(addr);
-        self.cpu.a = value;
-        self.flag_n(value);
-        self.flag_z(value);
-    }
-
-    pub fn ora(&mut self, addr: u16) {
-        let value = self.cpu.a | self.fetch_memory8(addr);
-        self.cpu.a = value;
-        self.flag_n(value);
-        self.flag_z(value);
+    pub fn is_unstable(self) -> bool {
+        matches!(
+            self,
+            Instruction::XAA
+                | Instruction::LXA
+                | Instruction::AHX
+                | Instruction::SHX
+                | Instruction::SHY
+                | Instruction::TAS
+        )
     }
+}
 
-    pub fn eor(&mut self, addr: u16) {
-        let value = self.cpu.a | self.fetch_memory8(addr);
-        self.cpu.a = value;
-        self.flag_n(value);
-        self.flag_z(value);
-    }
+// What to do when the CPU meets an opcode whose result depends on the chip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnstableOpcodes {
+    Emulate,
+    Reject,
+}
 
-    pub fn asl(&mut self, _addr: u8) {
-        if self.cpu.a & 0x80 > 0 {
-            self.set_c_flag()
-        } else {
-            self.clear_c_flag()
+impl<B: Bus> Cpu6502<B> {
+    // Runs an instruction that reads its operand, once the value is on the bus.
+    pub fn read_op(&mut self, instruction: Instruction, value: u8) {
+        match instruction {
+            Instruction::ADC => self.adc(value),
+            Instruction::SBC => self.sbc(value),
+            Instruction::AND => self.and(value),
+            Instruction::ORA => self.ora(value),
+            Instruction::EOR => self.eor(value),
+            Instruction::BIT => self.bit(value),
+            Instruction::LDA => self.lda(value),
+            Instruction::LDX => self.ldx(value),
+            Instruction::LDY => self.ldy(value),
+            Instruction::CMP => self.cmp(value),
+            Instruction::CPX => self.cpx(value),
+            Instruction::CPY => self.cpy(value),
+            Instruction::NOP => self.nop(),
+            Instruction::LAX => self.lax(value),
+            Instruction::ANC => self.anc(value),
+            Instruction::ALR => self.alr(value),
+            Instruction::ARR => self.arr(value),
+            Instruction::AXS => self.axs(value),
+            Instruction::LAS => self.las(value),
+            Instruction::XAA => self.xaa(value),
+            Instruction::LXA => self.lxa(value),
+            _ => unreachable!("{:?} does not read memory", instruction),
         }
-        self.cpu.a <<= 1;
-        self.flag_n(self.cpu.a);
-        self.flag_z(self.cpu.a);
     }
 
-    pub fn lsr(&mut self, _addr: u8) {
-        if self.cpu.a & 0x01 > 0 {
-            self.set_c_flag()
-        } else {
-            self.clear_c_flag()
+    // Returns the address and value a store instruction writes.
+    pub fn store_op(
+        &mut self,
+        instruction: Instruction,
+        addr: u16,
+        page_crossed: bool,
+    ) -> (u16, u8) {
+        match instruction {
+            Instruction::STA => (addr, self.cpu.a),
+            Instruction::STX => (addr, self.cpu.x),
+            Instruction::STY => (addr, self.cpu.y),
+            Instruction::SAX => (addr, self.cpu.a & self.cpu.x),
+            Instruction::AHX => self.ahx(addr, page_crossed),
+            Instruction::SHX => self.shx(addr, page_crossed),
+            Instruction::SHY => self.shy(addr, page_crossed),
+            Instruction::TAS => self.tas(addr, page_crossed),
+            _ => unreachable!("{:?} does not write memory", instruction),
         }
-        self.cpu.a >>= 1;
-        self.flag_n(self.cpu.a);
-        self.flag_z(self.cpu.a);
     }
 
-    pub fn rol(&mut self, _addr: u8) {
-        let c_flag = self.cpu.p & 0x01;
-        if self.cpu.a & 0x80 > 0 {
-            self.set_c_flag()
-        } else {
-            self.clear_c_flag()
+    // Runs a read-modify-write instruction and returns the value to write back.
+    // In accumulator mode the value comes from and goes back to A.
+    pub fn modify_op(&mut self, instruction: Instruction, value: u8) -> u8 {
+        match instruction {
+            Instruction::ASL => self.asl(value),
+            Instruction::LSR => self.lsr(value),
+            Instruction::ROL => self.rol(value),
+            Instruction::ROR => self.ror(value),
+            Instruction::INC => self.inc(value),
+            Instruction::DEC => self.dec(value),
+            Instruction::SLO => self.slo(value),
+            Instruction::RLA => self.rla(value),
+            Instruction::SRE => self.sre(value),
+            Instruction::RRA => self.rra(value),
+            Instruction::DCP => self.dcp(value),
+            Instruction::ISC => self.isc(value),
+            _ => unreachable!("{:?} does not modify memory", instruction),
         }
+    }
 
-        self.cpu.a <<= 1;
-        if c_flag > 0 {
-            self.cpu.a |= 0x01
-        } else {
-            self.cpu.a &= 0xfe
+    // Runs a single-byte instruction that only works on registers.
+    pub fn implied_op(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::INX => self.inx(),
+            Instruction::DEX => self.dex(),
+            Instruction::INY => self.iny(),
+            Instruction::DEY => self.dey(),
+            Instruction::SEC => self.sec(),
+            Instruction::CLI => self.cli(),
+            Instruction::CLC => self.clc(),
+            Instruction::SEI => self.sei(),
+            Instruction::CLD => self.cld(),
+            Instruction::SED => self.sed(),
+            Instruction::CLV => self.clv(),
+            Instruction::TAX => self.tax(),
+            Instruction::TAY => self.tay(),
+            Instruction::TXA => self.txa(),
+            Instruction::TYA => self.tya(),
+            Instruction::TSX => self.tsx(),
+            Instruction::TXS => self.txs(),
+            Instruction::NOP => self.nop(),
+            Instruction::KIL => self.kil(),
+            _ => unreachable!("{:?} is not a register instruction", instruction),
         }
-
-        self.flag_n(self.cpu.a);
-        self.flag_z(self.cpu.a)
     }
 
-    pub fn ror(&mut self, _addr: u8) {
-        let c_flag = self.cpu.p & 0x01;
-        if self.cpu.a & 0x01 > 0 {
-            self.set_c_flag()
+    pub fn adc(&mut self, value: u8) {
+        if self.decimal_mode() {
+            self.add_decimal(value)
         } else {
-            self.clear_c_flag()
+            self.add_with_carry(value)
         }
+    }
 
-        self.cpu.a >>= 1;
-        if c_flag > 0 {
-            self.cpu.a |= 0x80
+    // A - M - !C is the same as A + !M + C, so SBC shares the adder with ADC.
+    pub fn sbc(&mut self, value: u8) {
+        if self.decimal_mode() {
+            self.subtract_decimal(value)
         } else {
-            self.cpu.a &= 0x7f
+            self.add_with_carry(!value)
         }
-
-        self.flag_n(self.cpu.a);
-        self.flag_z(self.cpu.a);
     }
 
-    pub fn rti(&mut self) {
-        let sr = self.fetch_memory8(0x100 + self.cpu.s + 1);
-        self.cpu.s += 1;
-        self.cpu.p = sr;
-        let lower = self.fetch_memory8(0x100 + self.cpu.s + 1);
-        self.cpu.s += 1;
-        let upper = self.fetch_memory8(0x100 + self.cpu.s + 1);
-        self.cpu.s += 1;
-        self.cpu.pc = (upper as u16) << 8 | lower as u16;
-        self.ram[0x2000] |= 0x80;
+    // The 2A03 has the D flag but no decimal adder.
+    fn decimal_mode(&self) -> bool {
+        self.variant == Variant::Nmos6502 && self.cpu.p.contains(Status::D)
     }
 
-    pub fn bcc(&mut self, addr: u16) {
-        let c_flag = self.cpu.p & 0x01;
-        if c_flag == 0 {
-            self.cpu.pc = addr
-        }
+    fn add_with_carry(&mut self, value: u8) {
+        let c_flag = self.cpu.p.contains(Status::C) as u8;
+        let sum = self.cpu.a as u16 + value as u16 + c_flag as u16;
+        let result = sum as u8;
+        self.flag_v(self.cpu.a, value, result);
+        self.flag_c(sum > 0xff);
+        self.cpu.a = result;
+        self.flag_n(result);
+        self.flag_z(result);
     }
 
-    pub fn bcs(&mut self, addr: u16) {
-        let c_flag = self.cpu.p & 0x01;
-        if c_flag > 0 {
-            self.cpu.pc = addr
+    // NMOS BCD addition. Z comes from the binary sum, and N and V are taken
+    // after the low digit is adjusted but before the high one is.
+    fn add_decimal(&mut self, value: u8) {
+        let (a, value) = (self.cpu.a as u16, value as u16);
+        let c_flag = self.cpu.p.contains(Status::C) as u16;
+        let mut lower = (a & 0x0f) + (value & 0x0f) + c_flag;
+        if lower > 0x09 {
+            lower += 0x06
         }
-    }
-
-    pub fn beq(&mut self, addr: u16) {
-        let z_flag = self.cpu.p & 0x02;
-        if z_flag > 0 {
-            self.cpu.pc = addr
+        let carry = if lower > 0x0f { 0x10 } else { 0 };
+        let mut sum = (a & 0xf0) + (value & 0xf0) + carry + (lower & 0x0f);
+        self.flag_z((a + value + c_flag) as u8);
+        self.flag_n(sum as u8);
+        self.flag_v(a as u8, value as u8, sum as u8);
+        if sum > 0x9f {
+            sum += 0x60
         }
+        self.flag_c(sum > 0xff);
+        self.cpu.a = sum as u8;
     }
 
-    pub fn bne(&mut self, addr: u16) {
-        let z_flag = self.cpu.p & 0x02;
-        if z_flag == 0 {
-            self.cpu.pc = addr
+    // NMOS BCD subtraction. All flags come from the binary subtraction.
+    fn subtract_decimal(&mut self, value: u8) {
+        let a = self.cpu.a;
+        let borrow = !self.cpu.p.contains(Status::C) as u16;
+        self.add_with_carry(!value);
+
+        let mut lower = (a as u16 & 0x0f)
+            .wrapping_sub(value as u16 & 0x0f)
+            .wrapping_sub(borrow);
+        let mut upper = (a as u16 & 0xf0).wrapping_sub(value as u16 & 0xf0);
+        if lower & 0x10 != 0 {
+            lower = lower.wrapping_sub(0x06);
+            upper = upper.wrapping_sub(0x01);
         }
-    }
-
-    pub fn bmi(&mut self, addr: u16) {
-        let n_flag = self.cpu.p & 0x80;
-        if n_flag > 0 {
-            self.cpu.pc = addr
+        if upper & 0x0100 != 0 {
+            upper = upper.wrapping_sub(0x60);
         }
+        self.cpu.a = (lower & 0x0f | upper & 0xf0) as u8;
     }
 
-    pub fn bpl(&mut self, addr: u16) {
-        let n_flag = self.cpu.p & 0x80;
-        if n_flag == 0 {
-            self.cpu.pc = addr
-        }
+    pub fn and(&mut self, value: u8) {
+        let value = self.cpu.a & value;
+        self.cpu.a = value;
+        self.flag_n(value);
+        self.flag_z(value);
     }
 
-    pub fn bvc(&mut self, addr: u16) {
-        let v_flag = self.cpu.p & 0x40;
-        if v_flag == 0 {
-            self.cpu.pc = addr
-        }
-    }
-    pub fn bvs(&mut self, addr: u16) {
-        let v_flag = self.cpu.p & 0x40;
-        if v_flag > 0 {
-            self.cpu.pc = addr
-        }
+    pub fn ora(&mut self, value: u8) {
+        let value = self.cpu.a | value;
+        self.cpu.a = value;
+        self.flag_n(value);
+        self.flag_z(value);
     }
 
-    pub fn bit(&mut self, addr: u16) {
-        let value = self.fetch_memory8(addr);
-        self.flag_z(value & self.cpu.a);
+    pub fn eor(&mut self, value: u8) {
+        let value = self.cpu.a ^ value;
+        self.cpu.a = value;
         self.flag_n(value);
-        if (value & 0x40) != 0 {
-            self.cpu.p |= 0x40
-        } else {
-            self.cpu.p &= 0xbf
-        }
+        self.flag_z(value);
     }
 
-    pub fn jmp(&mut self, addr: u16) {
-        self.cpu.pc = addr
+    pub fn asl(&mut self, value: u8) -> u8 {
+        self.flag_c(value & 0x80 > 0);
+        self.flag_nz(value << 1)
     }
 
-    pub fn jsr(&mut self, addr: u16) {
-        let upper = (self.cpu.pc - 1) >> 8;
-        let lower = self.cpu.pc - 1;
-        self.set_memory8(0x100 + self.cpu.s, upper as u8);
-        self.set_memory8(0x100 + self.cpu.s - 1, lower as u8);
-        self.cpu.s -= 2;
-        self.cpu.pc = addr as u16;
+    pub fn lsr(&mut self, value: u8) -> u8 {
+        self.flag_c(value & 0x01 > 0);
+        self.flag_nz(value >> 1)
     }
 
-    pub fn rts(&mut self) {
-        let lower = self.fetch_memory8(0x100 + self.cpu.s as u16 + 1);
-        self.cpu.s += 1;
-        let upper = self.fetch_memory8(0x100 + self.cpu.s as u16 + 1);
-        self.cpu.s += 1;
-        self.cpu.pc = ((upper as u16) << 8 | lower as u16) as u16;
-        self.cpu.pc += 1;
+    pub fn rol(&mut self, value: u8) -> u8 {
+        let c_flag = self.cpu.p.contains(Status::C) as u8;
+        self.flag_c(value & 0x80 > 0);
+        self.flag_nz(value << 1 | c_flag)
     }
 
-    pub fn brk(&mut self) {
-        let iflag = self.cpu.p & 0x04;
-        if iflag == 0 {
-            self.cpu.p |= 0x10;
-            self.cpu.pc += 1;
-
-            let upper0 = (self.cpu.pc) >> 8;
-            let lower0 = self.cpu.pc;
-            self.set_memory8(0x100 + self.cpu.s as u16, upper0 as u8);
-            self.set_memory8(0x100 + self.cpu.s as u16 - 1, lower0 as u8);
-            self.set_memory8(0x100 + self.cpu.s as u16 - 2, self.cpu.p);
-            self.cpu.s -= 3;
-
-            self.cpu.p |= 0x04;
-
-            let upper1 = self.fetch_memory8(0xffff);
-            let lower1 = self.fetch_memory8(0xfffe);
-            self.cpu.pc = ((upper1 as u16) << 8) as u16 | lower1 as u16
-        }
+    pub fn ror(&mut self, value: u8) -> u8 {
+        let c_flag = self.cpu.p.contains(Status::C) as u8;
+        self.flag_c(value & 0x01 > 0);
+        self.flag_nz(value >> 1 | c_flag << 7)
     }
 
-    pub fn txs(&mut self) {
-        self.cpu.s = self.cpu.x as u16 | 0x0100;
-        self.flag_n(self.cpu.s as u8);
-        self.flag_z(self.cpu.s as u8)
+    // Sets N and Z from a result and passes it through.
+    fn flag_nz(&mut self, value: u8) -> u8 {
+        self.flag_n(value);
+        self.flag_z(value);
+        value
     }
 
-    pub fn sei(&mut self) {
-        self.cpu.p |= 0x04
+    // Whether a branch instruction is taken.
+    pub fn condition(&self, instruction: Instruction) -> bool {
+        let p = self.cpu.p;
+        match instruction {
+            Instruction::BCC => !p.contains(Status::C),
+            Instruction::BCS => p.contains(Status::C),
+            Instruction::BEQ => p.contains(Status::Z),
+            Instruction::BNE => !p.contains(Status::Z),
+            Instruction::BMI => p.contains(Status::N),
+            Instruction::BPL => !p.contains(Status::N),
+            Instruction::BVC => !p.contains(Status::V),
+            Instruction::BVS => p.contains(Status::V),
+            _ => unreachable!("{:?} is not a branch", instruction),
+        }
     }
 
-    pub fn lda(&mut self, addr: u16) {
-        if addr == 0x2007 {
-            self.cpu.a = self.ppu.ram[self.ppu.ptr as usize];
-            self.ppu.ptr += self.get_vram_delta()
-        } else {
-            self.cpu.a = self.fetch_memory8(addr);
-        }
-        self.flag_n(self.cpu.a);
-        self.flag_z(self.cpu.a);
+    pub fn bit(&mut self, value: u8) {
+        self.flag_z(value & self.cpu.a);
+        self.flag_n(value);
+        self.cpu.p.set(Status::V, value & 0x40 != 0)
     }
 
-    pub fn ldx(&mut self, addr: u16) {
-        if addr == 0x2007 {
-            self.cpu.x = self.ppu.ram[self.ppu.ptr as usize];
-            self.ppu.ptr += self.get_vram_delta()
-        } else {
-            self.cpu.x = self.fetch_memory8(addr);
-        }
-        self.flag_n(self.cpu.x);
-        self.flag_z(self.cpu.x);
+    pub fn txs(&mut self) {
+        self.cpu.s = self.cpu.x
     }
 
-    pub fn ldy(&mut self, addr: u16) {
-        if addr == 0x2007 {
-            self.cpu.y = self.ppu.ram[self.ppu.ptr as usize];
-            self.ppu.ptr += self.get_vram_delta()
-        } else {
-            self.cpu.y = self.fetch_memory8(addr);
-        }
-        self.flag_n(self.cpu.y);
-        self.flag_z(self.cpu.y);
+    pub fn sei(&mut self) {
+        self.flag_i(true)
     }
 
-    pub fn sta(&mut self, addr: u16) {
-        match addr {
-            0x2004 => {
-                self.ppu.s_ram[self.ram[0x2003] as usize] = self.cpu.a;
-                self.ram[0x2003] += 1
-            }
-            0x2005 => {
-                if self.ppu.scroll_flag {
-                    self.ppu.scroll[1] = self.cpu.a;
-                } else {
-                    self.ppu.scroll[0] = self.cpu.a;
-                    self.ppu.scroll_flag = true
-                }
-            }
-            PPU_ADDR => self.ppu.ptr = self.ppu.ptr << 8 | self.cpu.a as u16,
-            PPU_DATA => self.set_vram(self.cpu.a),
-            SPRITE_DMA => {
-                let start = (self.cpu.a as u16) << 8;
-                for i in 0..256 {
-                    self.ppu.s_ram[i] = self.fetch_memory8(start as u16 + i as u16)
-                }
-            }
-            0x2009 => (),
-            _ => (),
-        };
-        self.set_memory8(addr, self.cpu.a)
+    pub fn lda(&mut self, value: u8) {
+        self.cpu.a = self.flag_nz(value);
     }
 
-    pub fn stx(&mut self, addr: u16) {
-        match addr {
-            0x2004 => {
-                self.ppu.s_ram[self.ram[0x2003] as usize] = self.cpu.x;
-                self.ram[0x2003] += 1
-            }
-            0x2005 => {
-                if self.ppu.scroll_flag {
-                    self.ppu.scroll[1] = self.cpu.x;
-                } else {
-                    self.ppu.scroll[0] = self.cpu.x;
-                    self.ppu.scroll_flag = true
-                }
-            }
-            PPU_ADDR => self.ppu.ptr = self.ppu.ptr << 8 | self.cpu.x as u16,
-            PPU_DATA => self.set_vram(self.cpu.x),
-            SPRITE_DMA => {
-                let start = (self.cpu.x as u16) << 8;
-                for i in 0..256 {
-                    self.ppu.s_ram[i] = self.fetch_memory8(start as u16 + i as u16)
-                }
-            }
-            0x2009 => (),
-            _ => (),
-        };
-        self.set_memory8(addr, self.cpu.x)
+    pub fn ldx(&mut self, value: u8) {
+        self.cpu.x = self.flag_nz(value);
     }
 
-    pub fn sty(&mut self, addr: u16) {
-        match addr {
-            0x2004 => {
-                self.ppu.s_ram[self.ram[0x2003] as usize] = self.cpu.y;
-                self.ram[0x2003] += 1
-            }
-            0x2005 => {
-                if self.ppu.scroll_flag {
-                    self.ppu.scroll[1] = self.cpu.y;
-                } else {
-                    self.ppu.scroll[0] = self.cpu.y;
-                    self.ppu.scroll_flag = true
-                }
-            }
-            PPU_ADDR => self.ppu.ptr = self.ppu.ptr << 8 | self.cpu.y as u16,
-            PPU_DATA => self.set_vram(self.cpu.y),
-            SPRITE_DMA => {
-                let start = (self.cpu.y as u16) << 8;
-                for i in 0..256 {
-                    self.ppu.s_ram[i] = self.fetch_memory8(start as u16 + i as u16)
-                }
-            }
-            0x2009 => (),
-            _ => (),
-        };
-        self.set_memory8(addr, self.cpu.y)
+    pub fn ldy(&mut self, value: u8) {
+        self.cpu.y = self.flag_nz(value);
     }
 
     pub fn inx(&mut self) {
-        self.cpu.x += 1;
+        self.cpu.x = self.cpu.x.wrapping_add(1);
         self.flag_n(self.cpu.x);
         self.flag_z(self.cpu.x);
     }
 
     pub fn dex(&mut self) {
-        self.cpu.x -= 1;
+        self.cpu.x = self.cpu.x.wrapping_sub(1);
         self.flag_n(self.cpu.x);
         self.flag_z(self.cpu.x);
     }
 
     pub fn iny(&mut self) {
-        self.cpu.y += 1;
+        self.cpu.y = self.cpu.y.wrapping_add(1);
         self.flag_n(self.cpu.y);
         self.flag_z(self.cpu.y);
     }
 
     pub fn dey(&mut self) {
-        self.cpu.y -= 1;
+        self.cpu.y = self.cpu.y.wrapping_sub(1);
         self.flag_n(self.cpu.y);
         self.flag_z(self.cpu.y);
     }
 
-    pub fn cmp(&mut self, addr: u16) {
-        let value = self.cpu.a - self.fetch_memory8(addr);
-        let value16 = self.cpu.a - self.fetch_memory8(addr);
-
-        self.flag_n(value);
-        self.flag_z(value);
-        self.flag_c(Instruction::CMP, value16 as u16);
+    pub fn cmp(&mut self, value: u8) {
+        self.compare(self.cpu.a, value)
     }
 
-    pub fn cpx(&mut self, addr: u16) {
-        let value = self.cpu.x - self.fetch_memory8(addr);
-        let value16 = self.cpu.x - self.fetch_memory8(addr);
-
-        self.flag_n(value);
-        self.flag_z(value);
-        self.flag_c(Instruction::CPX, value16 as u16);
+    pub fn cpx(&mut self, value: u8) {
+        self.compare(self.cpu.x, value)
     }
 
-    pub fn cpy(&mut self, addr: u16) {
-        let value = self.cpu.y - self.fetch_memory8(addr);
-        let value16 = self.cpu.y - self.fetch_memory8(addr);
+    pub fn cpy(&mut self, value: u8) {
+        self.compare(self.cpu.y, value)
+    }
 
-        self.flag_n(value);
-        self.flag_z(value);
-        self.flag_c(Instruction::CPY, value16 as u16);
+    // Compares subtract without borrow in; C means no borrow out, i.e. register >= M.
+    fn compare(&mut self, register: u8, value: u8) {
+        let result = register.wrapping_sub(value);
+        self.flag_c(register >= value);
+        self.flag_n(result);
+        self.flag_z(result);
     }
 
-    pub fn inc(&mut self, addr: u16) {
-        self.set_memory8(addr, self.fetch_memory8(addr) + 1)
+    pub fn inc(&mut self, value: u8) -> u8 {
+        self.flag_nz(value.wrapping_add(1))
     }
 
-    pub fn dec(&mut self, addr: u16) {
-        self.set_memory8(addr, self.fetch_memory8(addr) - 1)
+    pub fn dec(&mut self, value: u8) -> u8 {
+        self.flag_nz(value.wrapping_sub(1))
     }
 
     pub fn sec(&mut self) {
-        self.cpu.p |= 0x01
+        self.flag_c(true)
     }
 
     pub fn cli(&mut self) {
         self.flag_i(false)
     }
 
-    pub fn clc(&mut self, addr: u16) {
-        self.flag_c(Instruction::CLC, addr)
+    pub fn clc(&mut self) {
+        self.flag_c(false)
     }
 
     pub fn cld(&mut self) {
@@ -496,7 +444,7 @@ impl Nes {
     }
 
     pub fn clv(&mut self) {
-        self.cpu.p &= 0xbf
+        self.cpu.p.set(Status::V, false)
     }
 
     pub fn tax(&mut self) {
@@ -524,68 +472,652 @@ impl Nes {
     }
 
     pub fn tsx(&mut self) {
-        self.cpu.x = self.cpu.s as u8;
+        self.cpu.x = self.cpu.s;
         self.flag_n(self.cpu.x);
         self.flag_z(self.cpu.x)
     }
 
-    pub fn pha(&mut self) {
-        self.set_memory8(0x100 + self.cpu.s, self.cpu.a);
-        self.cpu.s -= 1
+    pub fn pla(&mut self, value: u8) {
+        self.cpu.a = self.flag_nz(value);
+    }
+
+    pub fn plp(&mut self, value: u8) {
+        self.cpu.p = Status::from_bits(value);
     }
 
-    pub fn pla(&mut self) {
-        let value = self.fetch_memory8(0x0100 + self.cpu.s + 1);
+    pub fn nop(&mut self) {}
+
+    pub fn kil(&mut self) {
+        // the CPU locks up on the opcode until it is reset
+        let pc = self.cpu.pc.wrapping_sub(1);
+        self.cpu.pc = pc;
+        self.cpu.halted = Some(CpuError::Jammed {
+            pc,
+            opcode: self.peek_memory8(pc),
+        });
+    }
+
+    pub fn lax(&mut self, value: u8) {
         self.cpu.a = value;
-        self.cpu.s += 1;
-        self.flag_n(value);
-        self.flag_z(value);
+        self.cpu.x = self.flag_nz(value);
     }
 
-    pub fn php(&mut self) {
-        self.set_memory8(0x100 + self.cpu.s, self.cpu.p);
-        self.cpu.s -= 1
+    pub fn dcp(&mut self, value: u8) -> u8 {
+        let value = self.dec(value);
+        self.cmp(value);
+        value
     }
 
-    pub fn plp(&mut self) {
-        let value = self.fetch_memory8(0x0100 + self.cpu.s + 1);
-        self.cpu.p = value;
-        self.cpu.s += 1;
+    pub fn isc(&mut self, value: u8) -> u8 {
+        let value = self.inc(value);
+        self.sbc(value);
+        value
     }
 
-    pub fn nop(&mut self) {}
+    pub fn slo(&mut self, value: u8) -> u8 {
+        let value = self.asl(value);
+        self.ora(value);
+        value
+    }
 
-    fn set_vram(&mut self, value: u8) {
-        if self.ppu.ptr > 0x4000 {}
-        self.ppu.ram[self.ppu.ptr as usize] = value;
-        if self.ppu.ptr == 0x3f00
-            || self.ppu.ptr == 0x3f04
-            || self.ppu.ptr == 0x3f08
-            || self.ppu.ptr == 0x3f0c
-        {
-            self.ppu.ram[self.ppu.ptr as usize + 0x10] = value
-        } else if self.ppu.ptr == 0x3f10
-            || self.ppu.ptr == 0x3f14
-            || self.ppu.ptr == 0x3f18
-            || self.ppu.ptr == 0x3f1c
-        {
-            self.ppu.ram[self.ppu.ptr as usize - 0x10] = value
-        } else if !self.ppu.mirror
-            && ((0x2000 <= self.ppu.ptr && self.ppu.ptr < 0x2400)
-                || (0x2800 <= self.ppu.ptr && self.ppu.ptr < 0x2c00))
+    pub fn rla(&mut self, value: u8) -> u8 {
+        let value = self.rol(value);
+        self.and(value);
+        value
+    }
+
+    pub fn sre(&mut self, value: u8) -> u8 {
+        let value = self.lsr(value);
+        self.eor(value);
+        value
+    }
+
+    pub fn rra(&mut self, value: u8) -> u8 {
+        let value = self.ror(value);
+        self.adc(value);
+        value
+    }
+
+    pub fn anc(&mut self, value: u8) {
+        self.and(value);
+        self.flag_c(self.cpu.a & 0x80 > 0);
+    }
+
+    pub fn alr(&mut self, value: u8) {
+        self.and(value);
+        self.cpu.a = self.lsr(self.cpu.a);
+    }
+
+    pub fn arr(&mut self, value: u8) {
+        let c_flag = self.cpu.p.contains(Status::C) as u8;
+        let value = (self.cpu.a & value) >> 1 | c_flag << 7;
+        self.cpu.a = self.flag_nz(value);
+        // carry comes from bit 6 and overflow from bit 6 xor bit 5
+        self.flag_c(value & 0x40 > 0);
+        self.cpu
+            .p
+            .set(Status::V, ((value >> 6) ^ (value >> 5)) & 0x01 > 0)
+    }
+
+    pub fn axs(&mut self, value: u8) {
+        let ax = self.cpu.a & self.cpu.x;
+        self.flag_c(ax >= value);
+        self.cpu.x = self.flag_nz(ax.wrapping_sub(value));
+    }
+
+    pub fn las(&mut self, value: u8) {
+        let value = value & self.cpu.s;
+        self.cpu.a = value;
+        self.cpu.s = value;
+        self.cpu.x = self.flag_nz(value);
+    }
+
+    pub fn xaa(&mut self, value: u8) {
+        let value = (self.cpu.a | 0xee) & self.cpu.x & value;
+        self.cpu.a = self.flag_nz(value);
+    }
+
+    pub fn lxa(&mut self, value: u8) {
+        let value = (self.cpu.a | 0xee) & value;
+        self.cpu.a = value;
+        self.cpu.x = self.flag_nz(value);
+    }
+
+    pub fn ahx(&mut self, addr: u16, page_crossed: bool) -> (u16, u8) {
+        store_and_upper(addr, self.cpu.y, self.cpu.a & self.cpu.x, page_crossed)
+    }
+
+    pub fn shx(&mut self, addr: u16, page_crossed: bool) -> (u16, u8) {
+        store_and_upper(addr, self.cpu.y, self.cpu.x, page_crossed)
+    }
+
+    pub fn shy(&mut self, addr: u16, page_crossed: bool) -> (u16, u8) {
+        store_and_upper(addr, self.cpu.x, self.cpu.y, page_crossed)
+    }
+
+    pub fn tas(&mut self, addr: u16, page_crossed: bool) -> (u16, u8) {
+        self.cpu.s = self.cpu.a & self.cpu.x;
+        store_and_upper(addr, self.cpu.y, self.cpu.a & self.cpu.x, page_crossed)
+    }
+}
+
+// The stored value is ANDed with the high byte of the base address plus one,
+// and when indexing crossed a page that value also replaces the high byte.
+fn store_and_upper(addr: u16, index: u8, value: u8, page_crossed: bool) -> (u16, u8) {
+    let upper = (addr.wrapping_sub(index as u16) >> 8) as u8;
+    let value = value & upper.wrapping_add(1);
+    let addr = if page_crossed {
+        (value as u16) << 8 | (addr & 0x00ff)
+    } else {
+        addr
+    };
+    (addr, value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::UnstableOpcodes;
+    use crate::asm::{assemble, execute};
+    use crate::cpu::Variant;
+    use crate::error::CpuError;
+    use crate::flag::Status;
+    use crate::nes::Nes;
+
+    // Reference model working on plain integers, independent of the flag helpers.
+    fn reference_adc(a: u8, m: u8, c: u8) -> (u8, bool, bool) {
+        let unsigned = a as u16 + m as u16 + c as u16;
+        let signed = a as i8 as i16 + m as i8 as i16 + c as i16;
+        (
+            unsigned as u8,
+            unsigned > 0xff,
+            !(-128..=127).contains(&signed),
+        )
+    }
+
+    fn reference_sbc(a: u8, m: u8, c: u8) -> (u8, bool, bool) {
+        let borrow = 1 - c as i16;
+        let unsigned = a as i16 - m as i16 - borrow;
+        let signed = a as i8 as i16 - m as i8 as i16 - borrow;
+        (
+            unsigned as u8,
+            unsigned >= 0,
+            !(-128..=127).contains(&signed),
+        )
+    }
+
+    fn run(op: fn(&mut Nes, u8), expected: fn(u8, u8, u8) -> (u8, bool, bool)) {
+        let mut nes = Nes::default();
+        for a in 0..=0xff {
+            for m in 0..=0xff {
+                for c in 0..=1 {
+                    nes.cpu.a = a;
+                    nes.cpu.p = Status::from_bits(c);
+                    op(&mut nes, m);
+
+                    let (result, carry, overflow) = expected(a, m, c);
+                    let p = nes.cpu.p;
+                    assert_eq!(nes.cpu.a, result, "a={:02x} m={:02x} c={}", a, m, c);
+                    assert_eq!(
+                        p.contains(Status::C),
+                        carry,
+                        "C a={:02x} m={:02x} c={}",
+                        a,
+                        m,
+                        c
+                    );
+                    assert_eq!(
+                        p.contains(Status::V),
+                        overflow,
+                        "V a={:02x} m={:02x} c={}",
+                        a,
+                        m,
+                        c
+                    );
+                    assert_eq!(p.contains(Status::Z), result == 0);
+                    assert_eq!(p.contains(Status::N), result & 0x80 != 0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_adc() {
+        run(Nes::adc, reference_adc)
+    }
+
+    #[test]
+    fn test_sbc() {
+        run(Nes::sbc, reference_sbc)
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct Registers {
+        a: u8,
+        x: u8,
+        s: u8,
+        // the operand, or what a read-modify-write instruction stores
+        m: u8,
+        c: bool,
+        v: bool,
+        z: bool,
+        n: bool,
+    }
+
+    fn with_nz(r: Registers, value: u8) -> Registers {
+        Registers {
+            z: value == 0,
+            n: value & 0x80 != 0,
+            ..r
+        }
+    }
+
+    #[test]
+    fn test_unofficial() {
+        type Op = fn(&mut Nes, u8) -> u8;
+        type Reference = fn(Registers) -> Registers;
+        let cases: [(&str, Op, Reference); 11] = [
+            (
+                "ANC",
+                |nes, m| {
+                    nes.anc(m);
+                    m
+                },
+                |r| {
+                    let a = r.a & r.m;
+                    with_nz(
+                        Registers {
+                            a,
+                            c: a & 0x80 != 0,
+                            ..r
+                        },
+                        a,
+                    )
+                },
+            ),
+            (
+                "ALR",
+                |nes, m| {
+                    nes.alr(m);
+                    m
+                },
+                |r| {
+                    let a = (r.a & r.m) >> 1;
+                    with_nz(
+                        Registers {
+                            a,
+                            c: r.a & r.m & 0x01 != 0,
+                            ..r
+                        },
+                        a,
+                    )
+                },
+            ),
+            (
+                "ARR",
+                |nes, m| {
+                    nes.arr(m);
+                    m
+                },
+                |r| {
+                    let a = (r.a & r.m) >> 1 | (r.c as u8) << 7;
+                    let (bit6, bit5) = (a & 0x40 != 0, a & 0x20 != 0);
+                    with_nz(
+                        Registers {
+                            a,
+                            c: bit6,
+                            v: bit6 != bit5,
+                            ..r
+                        },
+                        a,
+                    )
+                },
+            ),
+            (
+                "AXS",
+                |nes, m| {
+                    nes.axs(m);
+                    m
+                },
+                |r| {
+                    let x = (r.a & r.x).wrapping_sub(r.m);
+                    with_nz(
+                        Registers {
+                            x,
+                            c: r.a & r.x >= r.m,
+                            ..r
+                        },
+                        x,
+                    )
+                },
+            ),
+            (
+                "LAS",
+                |nes, m| {
+                    nes.las(m);
+                    m
+                },
+                |r| {
+                    let value = r.m & r.s;
+                    let r = Registers {
+                        a: value,
+                        x: value,
+                        s: value,
+                        ..r
+                    };
+                    with_nz(r, value)
+                },
+            ),
+            ("SLO", Nes::slo, |r| {
+                let m = r.m << 1;
+                with_nz(
+                    Registers {
+                        a: r.a | m,
+                        m,
+                        c: r.m & 0x80 != 0,
+                        ..r
+                    },
+                    r.a | m,
+                )
+            }),
+            ("RLA", Nes::rla, |r| {
+                let m = r.m << 1 | r.c as u8;
+                with_nz(
+                    Registers {
+                        a: r.a & m,
+                        m,
+                        c: r.m & 0x80 != 0,
+                        ..r
+                    },
+                    r.a & m,
+                )
+            }),
+            ("SRE", Nes::sre, |r| {
+                let m = r.m >> 1;
+                with_nz(
+                    Registers {
+                        a: r.a ^ m,
+                        m,
+                        c: r.m & 0x01 != 0,
+                        ..r
+                    },
+                    r.a ^ m,
+                )
+            }),
+            ("RRA", Nes::rra, |r| {
+                let m = r.m >> 1 | (r.c as u8) << 7;
+                let (a, c, v) = reference_adc(r.a, m, r.m & 0x01);
+                with_nz(Registers { a, m, c, v, ..r }, a)
+            }),
+            ("DCP", Nes::dcp, |r| {
+                let m = r.m.wrapping_sub(1);
+                let r = Registers {
+                    m,
+                    c: r.a >= m,
+                    ..r
+                };
+                with_nz(r, r.a.wrapping_sub(m))
+            }),
+            ("ISC", Nes::isc, |r| {
+                let m = r.m.wrapping_add(1);
+                let (a, c, v) = reference_sbc(r.a, m, r.c as u8);
+                with_nz(Registers { a, m, c, v, ..r }, a)
+            }),
+        ];
+
+        // every A, operand and carry, with a few values of X and S
+        let mut nes = Nes::default();
+        for (name, op, expected) in cases.iter() {
+            for &x in [0xff, 0x0f, 0x3c].iter() {
+                for a in 0..=0xff {
+                    for m in 0..=0xff {
+                        for &c in [false, true].iter() {
+                            nes.cpu.a = a;
+                            nes.cpu.x = x;
+                            nes.cpu.s = x;
+                            nes.cpu.p = Status::from_bits(c as u8);
+                            let result = op(&mut nes, m);
+
+                            let p = nes.cpu.p;
+                            let actual = Registers {
+                                a: nes.cpu.a,
+                                x: nes.cpu.x,
+                                s: nes.cpu.s,
+                                m: result,
+                                c: p.contains(Status::C),
+                                v: p.contains(Status::V),
+                                z: p.contains(Status::Z),
+                                n: p.contains(Status::N),
+                            };
+                            let before = Registers {
+                                a,
+                                x,
+                                s: x,
+                                m,
+                                c,
+                                v: false,
+                                z: false,
+                                n: false,
+                            };
+                            assert_eq!(
+                                actual,
+                                expected(before),
+                                "{} a={:02x} x={:02x} m={:02x} c={}",
+                                name,
+                                a,
+                                x,
+                                m,
+                                c
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_decimal_mode() {
+        let mut nes = Nes::default();
+        nes.cpu.p = Status::from_bits(Status::D.bits());
+        nes.cpu.a = 0x09;
+        nes.adc(0x01);
+        assert_eq!(nes.cpu.a, 0x0a, "the 2A03 ignores D");
+
+        nes.variant = Variant::Nmos6502;
+        // (a, m, carry in) -> (result, carry out)
+        let adc = [
+            ((0x12, 0x34, false), (0x46, false)),
+            ((0x58, 0x46, true), (0x05, true)),
+            ((0x15, 0x26, false), (0x41, false)),
+            ((0x81, 0x92, false), (0x73, true)),
+        ];
+        let sbc = [
+            ((0x46, 0x12, true), (0x34, true)),
+            ((0x40, 0x13, true), (0x27, true)),
+            ((0x32, 0x02, false), (0x29, true)),
+            ((0x12, 0x21, true), (0x91, false)),
+            ((0x21, 0x34, true), (0x87, false)),
+        ];
+        for (cases, op) in [
+            (&adc[..], Nes::adc as fn(&mut Nes, u8)),
+            (&sbc[..], Nes::sbc),
+        ]
+        .iter()
         {
-            self.ppu.ram[self.ppu.ptr as usize + 0x0400] = value
-        } else if self.ppu.mirror && (0x2000 <= self.ppu.ptr && self.ppu.ptr < 0x2800) {
-            self.ppu.ram[self.ppu.ptr as usize + 0x0800] = value
+            for ((a, m, c), (result, carry)) in cases.iter() {
+                nes.cpu.a = *a;
+                nes.cpu.p.set(Status::C, *c);
+                op(&mut nes, *m);
+                assert_eq!(
+                    (nes.cpu.a, nes.cpu.p.contains(Status::C)),
+                    (*result, *carry)
+                );
+            }
         }
-        self.ppu.ptr += self.get_vram_delta()
+
+        // 99 + 00 + 1 wraps to 00, but Z and N reflect the intermediate sums
+        nes.cpu.a = 0x99;
+        nes.cpu.p.set(Status::C, true);
+        nes.adc(0x00);
+        assert_eq!(nes.cpu.a, 0x00);
+        assert!(nes.cpu.p.contains(Status::C | Status::N));
+        assert!(!nes.cpu.p.contains(Status::Z));
     }
 
-    fn get_vram_delta(&mut self) -> u16 {
-        let value = self.ram[0x2000];
-        if (value & 0x04) > 0 {
-            return 32;
+    #[test]
+    fn test_cmp() {
+        let mut nes = Nes::default();
+        for a in 0..=0xff {
+            for m in 0..=0xff {
+                nes.cpu.a = a;
+                nes.cmp(m);
+
+                let p = nes.cpu.p;
+                assert_eq!(p.contains(Status::C), a >= m, "a={:02x} m={:02x}", a, m);
+                assert_eq!(p.contains(Status::Z), a == m);
+                assert_eq!(p.contains(Status::N), a.wrapping_sub(m) & 0x80 != 0);
+            }
         }
-        1
+    }
+
+    #[test]
+    fn test_inc_dec_wrap() {
+        let mut nes = Nes::default();
+        assert_eq!(nes.inc(0xff), 0x00);
+        assert!(nes.cpu.p.contains(Status::Z));
+        assert_eq!(nes.dec(0x00), 0xff);
+        assert!(nes.cpu.p.contains(Status::N));
+
+        nes.cpu.x = 0x00;
+        nes.dex();
+        assert_eq!(nes.cpu.x, 0xff);
+        nes.inx();
+        assert_eq!(nes.cpu.x, 0x00);
+        nes.cpu.y = 0xff;
+        nes.iny();
+        assert_eq!(nes.cpu.y, 0x00);
+        nes.dey();
+        assert_eq!(nes.cpu.y, 0xff);
+    }
+
+    #[test]
+    fn test_shift_memory() {
+        let nes = execute(
+            "
+                    LDA #$81
+                    STA $10
+                    LDA #$01
+                    ASL $10
+                    LDX $10
+                    PHP
+                    ROR $10
+                    LDY $10
+                    LSR A
+            done:   NOP
+            ",
+        );
+        assert_eq!(nes.cpu.x, 0x02);
+        assert_eq!(nes.bus.ram[0x01fd] & 0x01, 0x01, "ASL shifted into C");
+        assert_eq!(nes.cpu.y, 0x81);
+        assert_eq!(nes.bus.ram[0x0010], 0x81);
+        assert_eq!(nes.cpu.a, 0x00);
+        assert!(nes.cpu.p.contains(Status::C | Status::Z));
+    }
+
+    #[test]
+    fn test_jsr_rts() {
+        let nes = execute(
+            "
+                    LDX #$00
+                    JSR count
+                    JSR count
+            done:   NOP
+            count:  INX
+                    RTS
+            ",
+        );
+        assert_eq!(nes.cpu.x, 2);
+        assert_eq!(nes.cpu.s, 0xfd);
+    }
+
+    #[test]
+    fn test_loop_and_store() {
+        let nes = execute(
+            "
+                    LDX #$04
+            loop:   TXA
+                    STA $0200,X
+                    DEX
+                    BNE loop
+            done:   NOP
+            ",
+        );
+        assert_eq!(nes.bus.ram[0x0201..0x0205], [1, 2, 3, 4]);
+        assert!(nes.cpu.p.contains(Status::Z));
+    }
+
+    #[test]
+    fn test_brk_rti() {
+        let nes = execute(
+            "
+            reset:  CLI
+                    BRK
+                    .db $ff          ; padding byte skipped by BRK
+            done:   NOP
+            irq:    PHP
+                    PLA
+                    STA $10
+                    LDY #$01
+                    RTI
+            ",
+        );
+        assert_eq!(nes.cpu.y, 1);
+        assert_eq!(nes.bus.ram[0x10] & 0x30, 0x30);
+        assert!(!nes.cpu.p.contains(Status::I));
+    }
+
+    #[test]
+    fn test_kil_jams() {
+        let program = assemble("LDX #$01\nKIL\nINX", 0x8000).unwrap();
+        let mut nes = Nes::default();
+        nes.load_program(&program);
+        nes.step().unwrap();
+
+        let jammed = CpuError::Jammed {
+            pc: 0x8002,
+            opcode: 0x02,
+        };
+        assert_eq!(nes.step(), Err(jammed.clone()));
+        assert_eq!(nes.cpu.halted, Some(jammed.clone()));
+        // the CPU stays locked up on the opcode
+        assert_eq!(nes.step(), Err(jammed.clone()));
+        assert_eq!(nes.tick(), Err(jammed));
+        assert_eq!((nes.cpu.pc, nes.cpu.x), (0x8002, 0x01));
+
+        nes.reset();
+        assert_eq!(nes.cpu.halted, None);
+        assert_eq!(nes.cpu.pc, 0x8000);
+        assert!(nes.step().is_ok());
+    }
+
+    #[test]
+    fn test_unstable_opcodes() {
+        let program = assemble("XAA #$ff\nNOP", 0x8000).unwrap();
+        let mut nes = Nes::default();
+        nes.load_program(&program);
+        assert!(
+            nes.step().is_ok(),
+            "unstable opcodes are emulated by default"
+        );
+
+        nes.unstable_opcodes = UnstableOpcodes::Reject;
+        nes.reset();
+        let rejected = CpuError::UnstableOpcode {
+            pc: 0x8000,
+            bytes: [0x8b, 0xff, 0xea],
+        };
+        assert_eq!(nes.step(), Err(rejected.clone()));
+        assert_eq!(nes.step(), Err(rejected));
+        assert_eq!(nes.cpu.pc, 0x8000, "the opcode was not run");
     }
 }
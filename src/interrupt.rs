@@ -0,0 +1,214 @@
+use crate::bus::Bus;
+use crate::cpu::Cpu6502;
+use crate::cycle::Sequence;
+use crate::flag::Status;
+
+pub const NMI_VECTOR: u16 = 0xfffa;
+pub const RESET_VECTOR: u16 = 0xfffc;
+pub const IRQ_VECTOR: u16 = 0xfffe;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqSource {
+    FrameCounter = 0x01,
+    Dmc = 0x02,
+    Mapper = 0x04,
+}
+
+#[derive(Debug, Default)]
+pub struct Interrupt {
+    // NMI is edge triggered: it is latched when the line goes from low to high
+    nmi_line: bool,
+    nmi_pending: bool,
+    // IRQ is level triggered and held while any source asserts it
+    irq_sources: u8,
+    // what the poll on the second-to-last cycle of the last instruction saw
+    polled: Option<u16>,
+}
+
+impl<B: Bus> Cpu6502<B> {
+    pub fn set_nmi_line(&mut self, active: bool) {
+        if active && !self.interrupt.nmi_line {
+            self.interrupt.nmi_pending = true
+        }
+        self.interrupt.nmi_line = active
+    }
+
+    pub fn set_irq(&mut self, source: IrqSource, active: bool) {
+        if active {
+            self.interrupt.irq_sources |= source as u8
+        } else {
+            self.interrupt.irq_sources &= !(source as u8)
+        }
+    }
+
+    pub fn irq_asserted(&self) -> bool {
+        self.interrupt.irq_sources != 0 || self.bus.irq_line()
+    }
+
+    // The vector of a pending NMI or IRQ, without acknowledging it. NMI wins
+    // when both are waiting.
+    pub fn poll_interrupts(&self) -> Option<u16> {
+        if self.interrupt.nmi_pending {
+            Some(NMI_VECTOR)
+        } else if self.irq_asserted() && !self.cpu.p.contains(Status::I) {
+            Some(IRQ_VECTOR)
+        } else {
+            None
+        }
+    }
+
+    // The CPU acts on the poll from the second-to-last cycle of an
+    // instruction, so CLI, SEI and PLP, which change I on their last cycle,
+    // only take effect after the next instruction.
+    pub(crate) fn latch_interrupt(&mut self, vector: Option<u16>) {
+        self.interrupt.polled = vector
+    }
+
+    // Starts servicing the latched interrupt, if any.
+    pub(crate) fn take_interrupt(&mut self) -> Option<u16> {
+        let vector = self.interrupt.polled.take();
+        if vector == Some(NMI_VECTOR) {
+            self.interrupt.nmi_pending = false
+        }
+        vector
+    }
+
+    // Reset goes through the same 7-cycle sequence as the other interrupts,
+    // but the stack writes are turned into reads, so only S moves.
+    pub fn reset(&mut self) {
+        self.cpu.s = self.cpu.s.wrapping_sub(3);
+        self.flag_i(true);
+        self.cpu.pc = self.fetch_vector(RESET_VECTOR);
+        self.advance_cycles(7);
+        self.cpu.halted = None;
+        self.sequence = None;
+        self.interrupt.nmi_pending = false;
+        self.interrupt.polled = None;
+    }
+
+    // Runs cycle `t` of the 7-cycle interrupt sequence, which pushes PC and
+    // P and jumps through `vector`. BRK enters it after its own two cycles;
+    // NMI and IRQ spend those on dummy reads instead of fetching an opcode.
+    // B is only set in the pushed P when the sequence was started by BRK.
+    pub(crate) fn interrupt_cycle(&mut self, s: &mut Sequence, vector: u16, brk: bool) -> bool {
+        match s.t {
+            0 | 1 => self.dummy_read(),
+            2 => self.push8((self.cpu.pc >> 8) as u8),
+            3 => self.push8(self.cpu.pc as u8),
+            4 => self.push8(self.cpu.p.to_stack(brk)),
+            5 => {
+                s.addr = self.fetch_memory8(vector) as u16;
+                self.flag_i(true);
+            }
+            _ => {
+                let upper = self.fetch_memory8(vector + 1);
+                self.cpu.pc = (upper as u16) << 8 | s.addr;
+                return true;
+            }
+        }
+        false
+    }
+
+    fn fetch_vector(&mut self, vector: u16) -> u16 {
+        let lower = self.fetch_memory8(vector);
+        let upper = self.fetch_memory8(vector + 1);
+        (upper as u16) << 8 | lower as u16
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::IrqSource;
+    use crate::asm::assemble;
+    use crate::cpu::STACK_PAGE;
+    use crate::instruction::Instruction;
+    use crate::nes::Nes;
+
+    fn load(source: &str) -> Nes {
+        let mut nes = Nes::default();
+        nes.load_program(&assemble(source, 0x8000).unwrap());
+        nes
+    }
+
+    // The instruction the next step ran, or `None` for an interrupt.
+    fn step(nes: &mut Nes) -> Option<Instruction> {
+        nes.step().unwrap().opcode.map(|opcode| opcode.instruction)
+    }
+
+    fn pushed_p(nes: &Nes) -> u8 {
+        nes.bus.ram[(STACK_PAGE | nes.cpu.s.wrapping_add(1) as u16) as usize]
+    }
+
+    #[test]
+    fn test_nmi_edge() {
+        let mut nes = load(
+            "
+            reset:  NOP
+                    NOP
+                    NOP
+                    NOP
+                    LDA #$80
+                    STA $2000
+                    NOP
+                    NOP
+            nmi:    RTI
+            ",
+        );
+        nes.bus.ppu.ctrl = 0x80;
+        nes.set_v_blank();
+        assert_eq!(step(&mut nes), Some(Instruction::NOP));
+        assert_eq!(step(&mut nes), None);
+        assert_eq!(nes.cpu.pc, 0x800b);
+        assert_eq!(pushed_p(&nes) & 0x30, 0x20, "B clear, bit 5 set");
+
+        // the line stays high, but there is only one NMI per rising edge
+        assert_eq!(step(&mut nes), Some(Instruction::RTI));
+        assert_eq!(step(&mut nes), Some(Instruction::NOP));
+        assert_eq!(step(&mut nes), Some(Instruction::NOP));
+
+        // enabling NMI in PPUCTRL during vblank raises the line as well
+        nes.clear_v_blank();
+        nes.bus.ppu.ctrl = 0x00;
+        nes.set_v_blank();
+        assert_eq!(step(&mut nes), Some(Instruction::NOP));
+        assert_eq!(step(&mut nes), Some(Instruction::LDA));
+        assert_eq!(step(&mut nes), Some(Instruction::STA));
+        // the write is on the last cycle of STA, after the poll
+        assert_eq!(step(&mut nes), Some(Instruction::NOP));
+        assert_eq!(step(&mut nes), None);
+        assert_eq!(nes.cpu.pc, 0x800b);
+    }
+
+    #[test]
+    fn test_irq_level() {
+        let mut nes = load(
+            "
+            reset:  NOP
+                    CLI
+                    NOP
+                    NOP
+                    NOP
+            irq:    RTI
+            ",
+        );
+        nes.set_irq(IrqSource::FrameCounter, true);
+        nes.set_irq(IrqSource::Dmc, true);
+        nes.set_irq(IrqSource::FrameCounter, false);
+        assert!(nes.irq_asserted(), "the DMC still holds the line");
+
+        // reset leaves I set, which masks the IRQ until CLI
+        assert_eq!(step(&mut nes), Some(Instruction::NOP));
+        assert_eq!(step(&mut nes), Some(Instruction::CLI));
+        assert_eq!(step(&mut nes), Some(Instruction::NOP));
+        assert_eq!(step(&mut nes), None);
+        assert_eq!(nes.cpu.pc, 0x8005);
+        assert_eq!(pushed_p(&nes) & 0x34, 0x20, "B and I clear, bit 5 set");
+
+        nes.set_irq(IrqSource::Dmc, false);
+        assert!(!nes.irq_asserted());
+        assert_eq!(step(&mut nes), Some(Instruction::RTI));
+        assert_eq!(step(&mut nes), Some(Instruction::NOP));
+        assert_eq!(step(&mut nes), Some(Instruction::NOP));
+        assert_eq!(nes.cpu.pc, 0x8005);
+    }
+}
@@ -1,8 +1,21 @@
 pub mod addressing;
+pub mod apu;
+pub mod asm;
+pub mod bus;
+pub mod cartridge;
+pub mod controller;
 pub mod cpu;
+pub mod cycle;
+pub mod disasm;
+pub mod dma;
+pub mod error;
 pub mod flag;
 pub mod instruction;
+pub mod interrupt;
 pub mod nes;
+pub mod observer;
+pub mod opcode;
 pub mod ppu;
 pub mod ram;
 pub mod render;
+pub mod trace;
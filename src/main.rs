@@ -1,8 +1,13 @@
+use rusen::bus::{FlatBus, Outcome};
+use rusen::cpu::{Cpu6502, Variant};
 use rusen::nes::Nes;
+use rusen::trace::Tracer;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
 
+// rusen <rom> [--trace <file>]
+// rusen <binary> --flat <load address> [--pc <address>] [--success <address>] [--trace <file>]
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = std::env::args().collect();
     let file_path = &args[1];
@@ -11,54 +16,67 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut f = File::open(file_path)?;
     let mut buffer = Vec::new();
     f.read_to_end(&mut buffer)?;
+    // `--trace <file>` writes a nestest.log style line per instruction
+    let tracer = match option(&args, "--trace")? {
+        Some(path) => Some(Tracer::file(path)?),
+        None => None,
+    };
+
+    // `--flat` runs a raw binary on an NMOS 6502 with 64K of RAM and no NES hardware
+    if let Some(load) = option(&args, "--flat")? {
+        let load = parse_address(load)?;
+        let pc = match option(&args, "--pc")? {
+            Some(pc) => parse_address(pc)?,
+            None => load,
+        };
+        let success = match option(&args, "--success")? {
+            Some(success) => Some(parse_address(success)?),
+            None => None,
+        };
+        let mut cpu: Cpu6502<FlatBus> = Cpu6502 {
+            variant: Variant::Nmos6502,
+            ..Cpu6502::default()
+        };
+        cpu.set_tracer(tracer);
+        cpu.load_binary(&buffer, load, pc);
+        return match cpu.run_until_trap(success)? {
+            Outcome::Success => {
+                println!(
+                    "success at ${:04X} after {} cycles",
+                    cpu.cpu.pc, cpu.cpu.cycles
+                );
+                Ok(())
+            }
+            Outcome::Trapped(pc) => Err(format!("trapped at ${:04X}", pc).into()),
+        };
+    }
+
     let mut nes = Nes::default();
+    nes.set_tracer(tracer);
     nes.load(buffer);
     nes.initialize();
     nes.reset();
 
     for _ in 0..180 {
-        nes.step();
+        nes.step()?;
     }
 
     nes.run(file_stem)?;
     Ok(())
 }
 
-pub fn run_cpu(nes: &mut Nes, end: u8) -> Result<(), Box<dyn std::error::Error>> {
-    let mut f = File::open("sample1.nes").expect("no file found");
-    let mut buffer = Vec::new();
-    f.read_to_end(&mut buffer).expect("buffer overflow");
-    println!("{:?}", buffer);
-    nes.load(buffer);
-    nes.initialize();
-
-    for i in 0..end {
-        println!("================ {} ================", i);
-        nes.step();
+fn option<'a>(args: &'a [String], name: &str) -> Result<Option<&'a String>, String> {
+    match args.iter().position(|arg| arg == name) {
+        Some(index) => match args.get(index + 1) {
+            Some(value) => Ok(Some(value)),
+            None => Err(format!("{} needs a value", name)),
+        },
+        None => Ok(None),
     }
-    Ok(())
 }
 
-#[cfg(test)]
-mod test {
-    use super::run_cpu;
-    use rusen::cpu::Cpu;
-    use rusen::nes::Nes;
-
-    #[test]
-    fn test_run_cpu() {
-        let mut nes = Nes::default();
-        run_cpu(&mut nes, 175).unwrap();
-        assert_eq!(
-            nes.cpu,
-            Cpu {
-                a: 0x1e,
-                x: 0x0d,
-                y: 0x00,
-                s: 0x01ff,
-                p: 0x34,
-                pc: 0x804e,
-            }
-        )
-    }
+// Accepts `$0400`, `0x0400` and plain hex.
+fn parse_address(text: &str) -> Result<u16, String> {
+    let hex = text.trim_start_matches('$').trim_start_matches("0x");
+    u16::from_str_radix(hex, 16).map_err(|_| format!("invalid address `{}`", text))
 }
@@ -1,18 +1,216 @@
-use crate::cpu::Cpu;
-use crate::ppu::Ppu;
+use crate::apu::{Apu, APU_STATUS, FRAME_COUNTER};
+use crate::bus::Bus;
+use crate::cartridge::Cartridge;
+use crate::controller::{Controller, CONTROLLER1, CONTROLLER2};
+use crate::cpu::Cpu6502;
+use crate::dma::Dma;
+use crate::ppu::{Ppu, SPRITE_DMA};
+use crate::ram::BusAccess;
 
-pub struct Nes {
-    pub cpu: Cpu,
+// The CPU core wired to the NES hardware.
+pub type Nes = Cpu6502<NesBus>;
+
+// The CPU memory map. Every access of the CPU, whether from a store, a
+// read-modify-write instruction, the stack or an interrupt, goes through
+// `read` and `write` here and reaches the component behind the address.
+//
+// $0000-$1FFF  2K of internal RAM, mirrored every $0800
+// $2000-$3FFF  the eight PPU registers, mirrored every 8 bytes
+// $4000-$401F  APU and I/O registers
+// $4020-$FFFF  the cartridge: expansion, PRG-RAM and PRG-ROM
+pub struct NesBus {
+    pub ram: [u8; 0x0800],
     pub ppu: Ppu,
-    pub ram: [u8; 0x10000],
+    pub apu: Apu,
+    pub controllers: [Controller; 2],
+    pub cartridge: Cartridge,
+    pub dma: Dma,
+    // The last value on the CPU data bus. Reads nothing answers return it,
+    // since the bus lines keep their charge for a while.
+    pub open_bus: u8,
+    // CPU cycles since power-up, the clock for the PPU I/O latch decay
+    pub cycles: u64,
 }
 
-impl Default for Nes {
+impl Default for NesBus {
     fn default() -> Self {
-        Nes {
-            cpu: Cpu::default(),
+        NesBus {
+            ram: [0; 0x0800],
             ppu: Ppu::default(),
-            ram: [0; 0x10000],
+            apu: Apu::default(),
+            controllers: [Controller::default(); 2],
+            cartridge: Cartridge::default(),
+            dma: Dma::default(),
+            open_bus: 0,
+            cycles: 0,
+        }
+    }
+}
+
+impl NesBus {
+    // The APU status and the controller ports are the only readable registers
+    // here; the rest is write-only or unused and reads as open bus. The
+    // controllers only drive the lower five bits.
+    fn read_io(&mut self, addr: u16) -> u8 {
+        match addr {
+            CONTROLLER1 => self.controllers[0].read() | self.open_bus & 0xe0,
+            CONTROLLER2 => self.controllers[1].read() | self.open_bus & 0xe0,
+            _ => self.peek_io(addr),
+        }
+    }
+
+    fn peek_io(&self, addr: u16) -> u8 {
+        match addr {
+            APU_STATUS => self.apu.read_status() | self.open_bus & 0x20,
+            CONTROLLER1 => self.controllers[0].peek() | self.open_bus & 0xe0,
+            CONTROLLER2 => self.controllers[1].peek() | self.open_bus & 0xe0,
+            _ => self.open_bus,
+        }
+    }
+
+    // $4016 strobes both controllers, while $4017 belongs to the APU frame
+    // counter on writes.
+    fn write_io(&mut self, addr: u16, value: u8) {
+        match addr {
+            SPRITE_DMA => self.start_oam_dma(value),
+            CONTROLLER1 => {
+                for controller in self.controllers.iter_mut() {
+                    controller.write(value)
+                }
+            }
+            0x4000..=0x4013 | APU_STATUS | FRAME_COUNTER => self.apu.write(addr, value),
+            _ => (),
+        }
+    }
+}
+
+impl Bus for NesBus {
+    fn read(&mut self, addr: u16) -> u8 {
+        let value = match addr {
+            0x0000..=0x1fff => self.ram[addr as usize & 0x07ff],
+            0x2000..=0x3fff => self.read_register(addr & 0x2007),
+            // $4015 is read inside the CPU and never reaches the data bus
+            APU_STATUS => return self.peek_io(addr),
+            0x4000..=0x401f => self.read_io(addr),
+            _ => self.cartridge.read(addr).unwrap_or(self.open_bus),
+        };
+        self.open_bus = value;
+        value
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        self.open_bus = value;
+        match addr {
+            0x0000..=0x1fff => self.ram[addr as usize & 0x07ff] = value,
+            0x2000..=0x3fff => self.write_register(addr & 0x2007, value),
+            0x4000..=0x401f => self.write_io(addr, value),
+            _ => self.cartridge.write(addr, value),
+        }
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x1fff => self.ram[addr as usize & 0x07ff],
+            0x2000..=0x3fff => self.peek_register(addr & 0x2007),
+            0x4000..=0x401f => self.peek_io(addr),
+            _ => self.cartridge.read(addr).unwrap_or(self.open_bus),
+        }
+    }
+
+    // RAM and ROM take the value; registers are not touched.
+    fn poke(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1fff => self.ram[addr as usize & 0x07ff] = value,
+            0x2000..=0x401f => (),
+            _ => self.cartridge.poke(addr, value),
         }
     }
+
+    fn tick(&mut self) {
+        self.cycles += 1
+    }
+
+    fn dma_cycle(&mut self, addr: u16) -> Option<BusAccess> {
+        self.run_dma(addr)
+    }
+
+    fn nmi_line(&self) -> bool {
+        self.nmi_output()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::asm::execute_with;
+    use crate::bus::Bus;
+    use crate::controller::CONTROLLER1;
+    use crate::nes::NesBus;
+    use crate::ppu::PPU_CTRL;
+
+    #[test]
+    fn test_memory_map() {
+        let mut bus = NesBus::default();
+        bus.write(0x0801, 0x12);
+        assert_eq!(bus.read(0x0001), 0x12);
+        assert_eq!(bus.read(0x1801), 0x12);
+
+        // $3FFE mirrors PPUADDR and $2008 PPUCTRL
+        bus.write(0x3ffe, 0x21);
+        bus.write(0x2006, 0x00);
+        assert_eq!(bus.ppu.ptr & 0x3fff, 0x2100);
+        bus.write(0x2008, 0x80);
+        assert_eq!(bus.ppu.ctrl, 0x80);
+        assert_eq!(bus.read(PPU_CTRL), 0x80, "PPUCTRL reads back the I/O latch");
+
+        bus.write(0x6000, 0x34);
+        assert_eq!(bus.read(0x6000), 0x34);
+
+        bus.cartridge.prg_rom = vec![0; 0x4000];
+        bus.cartridge.prg_rom[0x0010] = 0x56;
+        bus.write(0x8010, 0x00);
+        assert_eq!(bus.read(0x8010), 0x56, "ROM is not writable");
+        assert_eq!(bus.read(0xc010), 0x56, "16K of ROM is mirrored");
+    }
+
+    #[test]
+    fn test_read_modify_write_registers() {
+        let mut nes = execute_with(
+            "
+                    INC $2006       ; writes $00, then $01
+                    ASL $4014       ; reads open bus $40, DMA from page $40 then $80
+                    LDA #$01
+                    STA $4016
+                    LSR A
+                    STA $4016
+            done:   NOP
+            ",
+            |nes| nes.bus.controllers[0].buttons = 0x05,
+        );
+
+        assert_eq!(nes.bus.ppu.ptr, 0x0001);
+        assert!(!nes.bus.ppu.write_toggle);
+        // the program bytes of INC $2006
+        assert_eq!(nes.bus.ppu.s_ram[..3], [0xee, 0x06, 0x20]);
+
+        let bits: Vec<u8> = (0..9).map(|_| nes.bus.read(CONTROLLER1)).collect();
+        assert_eq!(bits, vec![1, 0, 1, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_open_bus() {
+        let nes = execute_with(
+            "
+                    LDX $4000       ; the upper byte of the operand is still on the bus
+                    LDY $5123       ; nothing in expansion space
+                    LDA #$01
+                    STA $4016
+                    LDA $4016
+            done:   NOP
+            ",
+            |nes| nes.bus.controllers[0].buttons = 0x01,
+        );
+        assert_eq!(nes.cpu.x, 0x40);
+        assert_eq!(nes.cpu.y, 0x51);
+        assert_eq!(nes.cpu.a, 0x41);
+    }
 }
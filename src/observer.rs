@@ -0,0 +1,177 @@
+use crate::bus::Bus;
+use crate::cpu::Cpu6502;
+use std::ops::RangeInclusive;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+    // the read of an opcode, as opposed to its operands or data
+    Fetch,
+}
+
+impl AccessKind {
+    const fn bit(self) -> u8 {
+        match self {
+            AccessKind::Read => 0x01,
+            AccessKind::Write => 0x02,
+            AccessKind::Fetch => 0x04,
+        }
+    }
+}
+
+// One CPU memory access as an observer sees it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryEvent {
+    pub addr: u16,
+    pub value: u8,
+    pub kind: AccessKind,
+    // CPU cycle the access happened on, counted since power-up
+    pub cycle: u64,
+}
+
+// Anything that wants to see memory accesses. Closures taking a
+// `&MemoryEvent` implement it.
+pub trait Observer {
+    fn observe(&mut self, event: &MemoryEvent);
+}
+
+impl<F: FnMut(&MemoryEvent)> Observer for F {
+    fn observe(&mut self, event: &MemoryEvent) {
+        self(event)
+    }
+}
+
+// Identifies an installed observer so it can be removed again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObserverId(u32);
+
+struct Registration {
+    id: ObserverId,
+    range: RangeInclusive<u16>,
+    kinds: u8,
+    observer: Box<dyn Observer>,
+}
+
+#[derive(Default)]
+pub struct Observers {
+    next_id: u32,
+    registrations: Vec<Registration>,
+}
+
+impl Observers {
+    pub fn is_empty(&self) -> bool {
+        self.registrations.is_empty()
+    }
+
+    fn notify(&mut self, event: &MemoryEvent) {
+        for registration in self.registrations.iter_mut() {
+            if registration.kinds & event.kind.bit() != 0
+                && registration.range.contains(&event.addr)
+            {
+                registration.observer.observe(event)
+            }
+        }
+    }
+}
+
+impl<B: Bus> Cpu6502<B> {
+    // Calls `observer` for every access of one of `kinds` inside `range`.
+    // Observers run in the order they were added.
+    pub fn add_observer<O: Observer + 'static>(
+        &mut self,
+        range: RangeInclusive<u16>,
+        kinds: &[AccessKind],
+        observer: O,
+    ) -> ObserverId {
+        let id = ObserverId(self.observers.next_id);
+        self.observers.next_id += 1;
+        self.observers.registrations.push(Registration {
+            id,
+            range,
+            kinds: kinds.iter().fold(0, |bits, kind| bits | kind.bit()),
+            observer: Box::new(observer),
+        });
+        id
+    }
+
+    // Returns whether the observer was still installed.
+    pub fn remove_observer(&mut self, id: ObserverId) -> bool {
+        let registrations = &mut self.observers.registrations;
+        let count = registrations.len();
+        registrations.retain(|registration| registration.id != id);
+        registrations.len() != count
+    }
+
+    // Without observers this is a single length check on the access path.
+    pub(crate) fn notify_observers(&mut self, addr: u16, value: u8, kind: AccessKind) {
+        if self.observers.is_empty() {
+            return;
+        }
+        let event = MemoryEvent {
+            addr,
+            value,
+            kind,
+            cycle: self.cpu.cycles,
+        };
+        self.observers.notify(&event)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AccessKind, MemoryEvent};
+    use crate::asm::execute_with;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_observers() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let fetches = Rc::new(RefCell::new(Vec::new()));
+        let mut data = None;
+        let mut nes = execute_with(
+            "
+                    LDA #$07
+                    STA $0300
+                    INC $0300
+                    LDX $0300
+            done:   NOP
+            ",
+            |nes| {
+                let sink = events.clone();
+                data = Some(nes.add_observer(
+                    0x0300..=0x03ff,
+                    &[AccessKind::Read, AccessKind::Write],
+                    move |event: &MemoryEvent| sink.borrow_mut().push((event.kind, event.value)),
+                ));
+                let sink = fetches.clone();
+                nes.add_observer(
+                    0x8000..=0xffff,
+                    &[AccessKind::Fetch],
+                    move |event: &MemoryEvent| sink.borrow_mut().push((event.addr, event.cycle)),
+                );
+            },
+        );
+        let data = data.unwrap();
+
+        use AccessKind::*;
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                (Write, 0x07),
+                (Read, 0x07),
+                (Write, 0x07),
+                (Write, 0x08),
+                (Read, 0x08)
+            ]
+        );
+        assert_eq!(
+            *fetches.borrow(),
+            vec![(0x8000, 7), (0x8002, 9), (0x8005, 13), (0x8008, 19)]
+        );
+
+        assert!(nes.remove_observer(data));
+        assert!(!nes.remove_observer(data));
+    }
+}
@@ -0,0 +1,391 @@
+use crate::addressing::AddressingMode;
+use crate::instruction::Instruction;
+
+// How an instruction touches its operand in memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Internal,
+    Read,
+    Write,
+    ReadModifyWrite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Opcode {
+    pub instruction: Instruction,
+    pub mode: AddressingMode,
+    pub bytes: u8,
+    // base cycle count, without page-crossing or branch penalties
+    pub cycles: u8,
+    pub access: Access,
+    // false for undocumented opcodes, including the extra NOP and SBC encodings
+    pub official: bool,
+}
+
+impl Opcode {
+    // Read instructions take one more cycle when an indexed address crosses a page.
+    pub fn page_penalty(&self) -> bool {
+        self.access == Access::Read
+    }
+}
+
+const fn op(
+    instruction: Instruction,
+    mode: AddressingMode,
+    cycles: u8,
+    access: Access,
+) -> Option<Opcode> {
+    Some(Opcode {
+        instruction,
+        mode,
+        bytes: mode.bytes(),
+        cycles,
+        access,
+        official: true,
+    })
+}
+
+const fn unofficial(
+    instruction: Instruction,
+    mode: AddressingMode,
+    cycles: u8,
+    access: Access,
+) -> Option<Opcode> {
+    Some(Opcode {
+        instruction,
+        mode,
+        bytes: mode.bytes(),
+        cycles,
+        access,
+        official: false,
+    })
+}
+
+pub fn decode(opcode: u8) -> Option<Opcode> {
+    OPCODES[opcode as usize]
+}
+
+pub static OPCODES: [Option<Opcode>; 256] = {
+    use Access::*;
+    use AddressingMode::*;
+    use Instruction::*;
+
+    let mut table = [None; 256];
+    table[0xa9] = op(LDA, Immediate, 2, Read);
+    table[0xa5] = op(LDA, ZeroPage, 3, Read);
+    table[0xb5] = op(LDA, ZeroPageX, 4, Read);
+    table[0xad] = op(LDA, Absolute, 4, Read);
+    table[0xbd] = op(LDA, AbsoluteX, 4, Read);
+    table[0xb9] = op(LDA, AbsoluteY, 4, Read);
+    table[0xa1] = op(LDA, IndirectX, 6, Read);
+    table[0xb1] = op(LDA, IndirectY, 5, Read);
+
+    table[0xa2] = op(LDX, Immediate, 2, Read);
+    table[0xa6] = op(LDX, ZeroPage, 3, Read);
+    table[0xb6] = op(LDX, ZeroPageY, 4, Read);
+    table[0xae] = op(LDX, Absolute, 4, Read);
+    table[0xbe] = op(LDX, AbsoluteY, 4, Read);
+
+    table[0xa0] = op(LDY, Immediate, 2, Read);
+    table[0xa4] = op(LDY, ZeroPage, 3, Read);
+    table[0xb4] = op(LDY, ZeroPageX, 4, Read);
+    table[0xac] = op(LDY, Absolute, 4, Read);
+    table[0xbc] = op(LDY, AbsoluteX, 4, Read);
+
+    table[0x85] = op(STA, ZeroPage, 3, Write);
+    table[0x95] = op(STA, ZeroPageX, 4, Write);
+    table[0x8d] = op(STA, Absolute, 4, Write);
+    table[0x9d] = op(STA, AbsoluteX, 5, Write);
+    table[0x99] = op(STA, AbsoluteY, 5, Write);
+    table[0x81] = op(STA, IndirectX, 6, Write);
+    table[0x91] = op(STA, IndirectY, 6, Write);
+
+    table[0x86] = op(STX, ZeroPage, 3, Write);
+    table[0x96] = op(STX, ZeroPageY, 4, Write);
+    table[0x8e] = op(STX, Absolute, 4, Write);
+
+    table[0x84] = op(STY, ZeroPage, 3, Write);
+    table[0x94] = op(STY, ZeroPageX, 4, Write);
+    table[0x8c] = op(STY, Absolute, 4, Write);
+
+    table[0xaa] = op(TAX, Implied, 2, Internal);
+
+    table[0xa8] = op(TAY, Implied, 2, Internal);
+
+    table[0xba] = op(TSX, Implied, 2, Internal);
+
+    table[0x8a] = op(TXA, Implied, 2, Internal);
+
+    table[0x9a] = op(TXS, Implied, 2, Internal);
+
+    table[0x98] = op(TYA, Implied, 2, Internal);
+
+    table[0x69] = op(ADC, Immediate, 2, Read);
+    table[0x65] = op(ADC, ZeroPage, 3, Read);
+    table[0x75] = op(ADC, ZeroPageX, 4, Read);
+    table[0x6d] = op(ADC, Absolute, 4, Read);
+    table[0x7d] = op(ADC, AbsoluteX, 4, Read);
+    table[0x79] = op(ADC, AbsoluteY, 4, Read);
+    table[0x61] = op(ADC, IndirectX, 6, Read);
+    table[0x71] = op(ADC, IndirectY, 5, Read);
+
+    table[0x29] = op(AND, Immediate, 2, Read);
+    table[0x25] = op(AND, ZeroPage, 3, Read);
+    table[0x35] = op(AND, ZeroPageX, 4, Read);
+    table[0x2d] = op(AND, Absolute, 4, Read);
+    table[0x3d] = op(AND, AbsoluteX, 4, Read);
+    table[0x39] = op(AND, AbsoluteY, 4, Read);
+    table[0x21] = op(AND, IndirectX, 6, Read);
+    table[0x31] = op(AND, IndirectY, 5, Read);
+
+    table[0x0a] = op(ASL, Accumulator, 2, Internal);
+    table[0x06] = op(ASL, ZeroPage, 5, ReadModifyWrite);
+    table[0x16] = op(ASL, ZeroPageX, 6, ReadModifyWrite);
+    table[0x0e] = op(ASL, Absolute, 6, ReadModifyWrite);
+    table[0x1e] = op(ASL, AbsoluteX, 7, ReadModifyWrite);
+
+    table[0x24] = op(BIT, ZeroPage, 3, Read);
+    table[0x2c] = op(BIT, Absolute, 4, Read);
+
+    table[0xc9] = op(CMP, Immediate, 2, Read);
+    table[0xc5] = op(CMP, ZeroPage, 3, Read);
+    table[0xd5] = op(CMP, ZeroPageX, 4, Read);
+    table[0xcd] = op(CMP, Absolute, 4, Read);
+    table[0xdd] = op(CMP, AbsoluteX, 4, Read);
+    table[0xd9] = op(CMP, AbsoluteY, 4, Read);
+    table[0xc1] = op(CMP, IndirectX, 6, Read);
+    table[0xd1] = op(CMP, IndirectY, 5, Read);
+
+    table[0xe0] = op(CPX, Immediate, 2, Read);
+    table[0xe4] = op(CPX, ZeroPage, 3, Read);
+    table[0xec] = op(CPX, Absolute, 4, Read);
+
+    table[0xc0] = op(CPY, Immediate, 2, Read);
+    table[0xc4] = op(CPY, ZeroPage, 3, Read);
+    table[0xcc] = op(CPY, Absolute, 4, Read);
+
+    table[0xc6] = op(DEC, ZeroPage, 5, ReadModifyWrite);
+    table[0xd6] = op(DEC, ZeroPageX, 6, ReadModifyWrite);
+    table[0xce] = op(DEC, Absolute, 6, ReadModifyWrite);
+    table[0xde] = op(DEC, AbsoluteX, 7, ReadModifyWrite);
+
+    table[0xca] = op(DEX, Implied, 2, Internal);
+
+    table[0x88] = op(DEY, Implied, 2, Internal);
+
+    table[0x49] = op(EOR, Immediate, 2, Read);
+    table[0x45] = op(EOR, ZeroPage, 3, Read);
+    table[0x55] = op(EOR, ZeroPageX, 4, Read);
+    table[0x4d] = op(EOR, Absolute, 4, Read);
+    table[0x5d] = op(EOR, AbsoluteX, 4, Read);
+    table[0x59] = op(EOR, AbsoluteY, 4, Read);
+    table[0x41] = op(EOR, IndirectX, 6, Read);
+    table[0x51] = op(EOR, IndirectY, 5, Read);
+
+    table[0xe6] = op(INC, ZeroPage, 5, ReadModifyWrite);
+    table[0xf6] = op(INC, ZeroPageX, 6, ReadModifyWrite);
+    table[0xee] = op(INC, Absolute, 6, ReadModifyWrite);
+    table[0xfe] = op(INC, AbsoluteX, 7, ReadModifyWrite);
+
+    table[0xe8] = op(INX, Implied, 2, Internal);
+
+    table[0xc8] = op(INY, Implied, 2, Internal);
+
+    table[0x4a] = op(LSR, Accumulator, 2, Internal);
+    table[0x46] = op(LSR, ZeroPage, 5, ReadModifyWrite);
+    table[0x56] = op(LSR, ZeroPageX, 6, ReadModifyWrite);
+    table[0x4e] = op(LSR, Absolute, 6, ReadModifyWrite);
+    table[0x5e] = op(LSR, AbsoluteX, 7, ReadModifyWrite);
+
+    table[0x09] = op(ORA, Immediate, 2, Read);
+    table[0x05] = op(ORA, ZeroPage, 3, Read);
+    table[0x15] = op(ORA, ZeroPageX, 4, Read);
+    table[0x0d] = op(ORA, Absolute, 4, Read);
+    table[0x1d] = op(ORA, AbsoluteX, 4, Read);
+    table[0x19] = op(ORA, AbsoluteY, 4, Read);
+    table[0x01] = op(ORA, IndirectX, 6, Read);
+    table[0x11] = op(ORA, IndirectY, 5, Read);
+
+    table[0x2a] = op(ROL, Accumulator, 2, Internal);
+    table[0x26] = op(ROL, ZeroPage, 5, ReadModifyWrite);
+    table[0x36] = op(ROL, ZeroPageX, 6, ReadModifyWrite);
+    table[0x2e] = op(ROL, Absolute, 6, ReadModifyWrite);
+    table[0x3e] = op(ROL, AbsoluteX, 7, ReadModifyWrite);
+
+    table[0x6a] = op(ROR, Accumulator, 2, Internal);
+    table[0x66] = op(ROR, ZeroPage, 5, ReadModifyWrite);
+    table[0x76] = op(ROR, ZeroPageX, 6, ReadModifyWrite);
+    table[0x6e] = op(ROR, Absolute, 6, ReadModifyWrite);
+    table[0x7e] = op(ROR, AbsoluteX, 7, ReadModifyWrite);
+
+    table[0xe9] = op(SBC, Immediate, 2, Read);
+    table[0xe5] = op(SBC, ZeroPage, 3, Read);
+    table[0xf5] = op(SBC, ZeroPageX, 4, Read);
+    table[0xed] = op(SBC, Absolute, 4, Read);
+    table[0xfd] = op(SBC, AbsoluteX, 4, Read);
+    table[0xf9] = op(SBC, AbsoluteY, 4, Read);
+    table[0xe1] = op(SBC, IndirectX, 6, Read);
+    table[0xf1] = op(SBC, IndirectY, 5, Read);
+
+    table[0x48] = op(PHA, Implied, 3, Internal);
+    table[0x08] = op(PHP, Implied, 3, Internal);
+    table[0x68] = op(PLA, Implied, 4, Internal);
+    table[0x28] = op(PLP, Implied, 4, Internal);
+
+    table[0x4c] = op(JMP, Absolute, 3, Internal);
+    table[0x6c] = op(JMP, Indirect, 5, Internal);
+
+    table[0x20] = op(JSR, Absolute, 6, Internal);
+    table[0x60] = op(RTS, Implied, 6, Internal);
+    table[0x40] = op(RTI, Implied, 6, Internal);
+
+    table[0x90] = op(BCC, Relative, 2, Internal);
+    table[0xb0] = op(BCS, Relative, 2, Internal);
+    table[0xf0] = op(BEQ, Relative, 2, Internal);
+    table[0x30] = op(BMI, Relative, 2, Internal);
+    table[0xd0] = op(BNE, Relative, 2, Internal);
+    table[0x10] = op(BPL, Relative, 2, Internal);
+    table[0x50] = op(BVC, Relative, 2, Internal);
+    table[0x70] = op(BVS, Relative, 2, Internal);
+
+    table[0x18] = op(CLC, Implied, 2, Internal);
+    table[0xd8] = op(CLD, Implied, 2, Internal);
+    table[0x58] = op(CLI, Implied, 2, Internal);
+    table[0xb8] = op(CLV, Implied, 2, Internal);
+    table[0x38] = op(SEC, Implied, 2, Internal);
+    table[0xf8] = op(SED, Implied, 2, Internal);
+    table[0x78] = op(SEI, Implied, 2, Internal);
+
+    table[0x00] = op(BRK, Implied, 7, Internal);
+    table[0xea] = op(NOP, Implied, 2, Internal);
+
+    // unofficial
+    table[0x1a] = unofficial(NOP, Implied, 2, Internal);
+    table[0x3a] = unofficial(NOP, Implied, 2, Internal);
+    table[0x5a] = unofficial(NOP, Implied, 2, Internal);
+    table[0x7a] = unofficial(NOP, Implied, 2, Internal);
+    table[0xda] = unofficial(NOP, Implied, 2, Internal);
+    table[0xfa] = unofficial(NOP, Implied, 2, Internal);
+    table[0x80] = unofficial(NOP, Immediate, 2, Read);
+    table[0x82] = unofficial(NOP, Immediate, 2, Read);
+    table[0x89] = unofficial(NOP, Immediate, 2, Read);
+    table[0xc2] = unofficial(NOP, Immediate, 2, Read);
+    table[0xe2] = unofficial(NOP, Immediate, 2, Read);
+    table[0x04] = unofficial(NOP, ZeroPage, 3, Read);
+    table[0x44] = unofficial(NOP, ZeroPage, 3, Read);
+    table[0x64] = unofficial(NOP, ZeroPage, 3, Read);
+    table[0x14] = unofficial(NOP, ZeroPageX, 4, Read);
+    table[0x34] = unofficial(NOP, ZeroPageX, 4, Read);
+    table[0x54] = unofficial(NOP, ZeroPageX, 4, Read);
+    table[0x74] = unofficial(NOP, ZeroPageX, 4, Read);
+    table[0xd4] = unofficial(NOP, ZeroPageX, 4, Read);
+    table[0xf4] = unofficial(NOP, ZeroPageX, 4, Read);
+    table[0x0c] = unofficial(NOP, Absolute, 4, Read);
+    table[0x1c] = unofficial(NOP, AbsoluteX, 4, Read);
+    table[0x3c] = unofficial(NOP, AbsoluteX, 4, Read);
+    table[0x5c] = unofficial(NOP, AbsoluteX, 4, Read);
+    table[0x7c] = unofficial(NOP, AbsoluteX, 4, Read);
+    table[0xdc] = unofficial(NOP, AbsoluteX, 4, Read);
+    table[0xfc] = unofficial(NOP, AbsoluteX, 4, Read);
+
+    table[0xa7] = unofficial(LAX, ZeroPage, 3, Read);
+    table[0xb7] = unofficial(LAX, ZeroPageY, 4, Read);
+    table[0xaf] = unofficial(LAX, Absolute, 4, Read);
+    table[0xbf] = unofficial(LAX, AbsoluteY, 4, Read);
+    table[0xa3] = unofficial(LAX, IndirectX, 6, Read);
+    table[0xb3] = unofficial(LAX, IndirectY, 5, Read);
+
+    table[0x87] = unofficial(SAX, ZeroPage, 3, Write);
+    table[0x97] = unofficial(SAX, ZeroPageY, 4, Write);
+    table[0x8f] = unofficial(SAX, Absolute, 4, Write);
+    table[0x83] = unofficial(SAX, IndirectX, 6, Write);
+
+    table[0x07] = unofficial(SLO, ZeroPage, 5, ReadModifyWrite);
+    table[0x17] = unofficial(SLO, ZeroPageX, 6, ReadModifyWrite);
+    table[0x0f] = unofficial(SLO, Absolute, 6, ReadModifyWrite);
+    table[0x1f] = unofficial(SLO, AbsoluteX, 7, ReadModifyWrite);
+    table[0x1b] = unofficial(SLO, AbsoluteY, 7, ReadModifyWrite);
+    table[0x03] = unofficial(SLO, IndirectX, 8, ReadModifyWrite);
+    table[0x13] = unofficial(SLO, IndirectY, 8, ReadModifyWrite);
+
+    table[0x27] = unofficial(RLA, ZeroPage, 5, ReadModifyWrite);
+    table[0x37] = unofficial(RLA, ZeroPageX, 6, ReadModifyWrite);
+    table[0x2f] = unofficial(RLA, Absolute, 6, ReadModifyWrite);
+    table[0x3f] = unofficial(RLA, AbsoluteX, 7, ReadModifyWrite);
+    table[0x3b] = unofficial(RLA, AbsoluteY, 7, ReadModifyWrite);
+    table[0x23] = unofficial(RLA, IndirectX, 8, ReadModifyWrite);
+    table[0x33] = unofficial(RLA, IndirectY, 8, ReadModifyWrite);
+
+    table[0x47] = unofficial(SRE, ZeroPage, 5, ReadModifyWrite);
+    table[0x57] = unofficial(SRE, ZeroPageX, 6, ReadModifyWrite);
+    table[0x4f] = unofficial(SRE, Absolute, 6, ReadModifyWrite);
+    table[0x5f] = unofficial(SRE, AbsoluteX, 7, ReadModifyWrite);
+    table[0x5b] = unofficial(SRE, AbsoluteY, 7, ReadModifyWrite);
+    table[0x43] = unofficial(SRE, IndirectX, 8, ReadModifyWrite);
+    table[0x53] = unofficial(SRE, IndirectY, 8, ReadModifyWrite);
+
+    table[0x67] = unofficial(RRA, ZeroPage, 5, ReadModifyWrite);
+    table[0x77] = unofficial(RRA, ZeroPageX, 6, ReadModifyWrite);
+    table[0x6f] = unofficial(RRA, Absolute, 6, ReadModifyWrite);
+    table[0x7f] = unofficial(RRA, AbsoluteX, 7, ReadModifyWrite);
+    table[0x7b] = unofficial(RRA, AbsoluteY, 7, ReadModifyWrite);
+    table[0x63] = unofficial(RRA, IndirectX, 8, ReadModifyWrite);
+    table[0x73] = unofficial(RRA, IndirectY, 8, ReadModifyWrite);
+
+    table[0xc7] = unofficial(DCP, ZeroPage, 5, ReadModifyWrite);
+    table[0xd7] = unofficial(DCP, ZeroPageX, 6, ReadModifyWrite);
+    table[0xcf] = unofficial(DCP, Absolute, 6, ReadModifyWrite);
+    table[0xdf] = unofficial(DCP, AbsoluteX, 7, ReadModifyWrite);
+    table[0xdb] = unofficial(DCP, AbsoluteY, 7, ReadModifyWrite);
+    table[0xc3] = unofficial(DCP, IndirectX, 8, ReadModifyWrite);
+    table[0xd3] = unofficial(DCP, IndirectY, 8, ReadModifyWrite);
+
+    table[0xe7] = unofficial(ISC, ZeroPage, 5, ReadModifyWrite);
+    table[0xf7] = unofficial(ISC, ZeroPageX, 6, ReadModifyWrite);
+    table[0xef] = unofficial(ISC, Absolute, 6, ReadModifyWrite);
+    table[0xff] = unofficial(ISC, AbsoluteX, 7, ReadModifyWrite);
+    table[0xfb] = unofficial(ISC, AbsoluteY, 7, ReadModifyWrite);
+    table[0xe3] = unofficial(ISC, IndirectX, 8, ReadModifyWrite);
+    table[0xf3] = unofficial(ISC, IndirectY, 8, ReadModifyWrite);
+
+    table[0x0b] = unofficial(ANC, Immediate, 2, Read);
+    table[0x2b] = unofficial(ANC, Immediate, 2, Read);
+
+    table[0x4b] = unofficial(ALR, Immediate, 2, Read);
+
+    table[0x6b] = unofficial(ARR, Immediate, 2, Read);
+
+    table[0xcb] = unofficial(AXS, Immediate, 2, Read);
+
+    table[0xeb] = unofficial(SBC, Immediate, 2, Read);
+
+    table[0xbb] = unofficial(LAS, AbsoluteY, 4, Read);
+
+    table[0x8b] = unofficial(XAA, Immediate, 2, Read);
+
+    table[0xab] = unofficial(LXA, Immediate, 2, Read);
+
+    table[0x9f] = unofficial(AHX, AbsoluteY, 5, Write);
+    table[0x93] = unofficial(AHX, IndirectY, 6, Write);
+
+    table[0x9e] = unofficial(SHX, AbsoluteY, 5, Write);
+
+    table[0x9c] = unofficial(SHY, AbsoluteX, 5, Write);
+
+    table[0x9b] = unofficial(TAS, AbsoluteY, 5, Write);
+
+    table[0x02] = unofficial(KIL, Implied, 2, Internal);
+    table[0x12] = unofficial(KIL, Implied, 2, Internal);
+    table[0x22] = unofficial(KIL, Implied, 2, Internal);
+    table[0x32] = unofficial(KIL, Implied, 2, Internal);
+    table[0x42] = unofficial(KIL, Implied, 2, Internal);
+    table[0x52] = unofficial(KIL, Implied, 2, Internal);
+    table[0x62] = unofficial(KIL, Implied, 2, Internal);
+    table[0x72] = unofficial(KIL, Implied, 2, Internal);
+    table[0x92] = unofficial(KIL, Implied, 2, Internal);
+    table[0xb2] = unofficial(KIL, Implied, 2, Internal);
+    table[0xd2] = unofficial(KIL, Implied, 2, Internal);
+    table[0xf2] = unofficial(KIL, Implied, 2, Internal);
+
+    table
+};
@@ -1,7 +1,25 @@
-use crate::nes::Nes;
+use crate::nes::NesBus;
 use crate::render::GridPosition;
 use ggez::graphics::{self, MeshBuilder};
 
+pub const PPU_CTRL: u16 = 0x2000;
+pub const PPU_MASK: u16 = 0x2001;
+pub const PPU_STATUS: u16 = 0x2002;
+pub const OAM_ADDR: u16 = 0x2003;
+pub const OAM_DATA: u16 = 0x2004;
+pub const PPU_SCROLL: u16 = 0x2005;
+pub const PPU_ADDR: u16 = 0x2006;
+pub const PPU_DATA: u16 = 0x2007;
+pub const SPRITE_DMA: u16 = 0x4014;
+
+// PPUSTATUS bits; the lower five are not driven by the status register
+pub const STATUS_VBLANK: u8 = 0x80;
+pub const STATUS_SPRITE_ZERO_HIT: u8 = 0x40;
+pub const STATUS_SPRITE_OVERFLOW: u8 = 0x20;
+
+// CPU cycles a bit of the I/O latch holds its charge, about 600ms
+const LATCH_DECAY_CYCLES: u64 = 1_073_864;
+
 pub const COLORS: [[u8; 3]; 64] = [
     [0x80, 0x80, 0x80],
     [0x00, 0x3D, 0xA6],
@@ -70,32 +88,153 @@ pub const COLORS: [[u8; 3]; 64] = [
 ];
 
 pub struct Ppu {
+    pub ctrl: u8,
+    pub mask: u8,
+    pub status: u8,
+    pub oam_addr: u8,
     pub ram: [u8; 0x4000],
     pub s_ram: [u8; 0x100],
     pub mirror: bool,
     pub ptr: u16,
+    // PPUDATA reads return this and refill it from VRAM
     pub ppudata_buf: u8,
     pub scroll: [u8; 2],
-    pub scroll_flag: bool,
+    // shared by PPUSCROLL and PPUADDR, set between the two writes of a pair
+    pub write_toggle: bool,
+    // The I/O latch on the PPU data bus. Every access refreshes the bits it
+    // drives, and each bit decays to 0 on its own when left alone.
+    pub io_latch: u8,
+    pub latch_refreshed: [u64; 8],
     pub raster: u16,
 }
 
 impl Default for Ppu {
     fn default() -> Self {
         Ppu {
+            ctrl: 0,
+            mask: 0,
+            status: 0,
+            oam_addr: 0,
             ram: [0; 0x4000],
             s_ram: [0; 0x100],
             mirror: false,
             ptr: 1,
             ppudata_buf: 1,
             scroll: [0; 2],
-            scroll_flag: false,
+            write_toggle: false,
+            io_latch: 0,
+            latch_refreshed: [0; 8],
             raster: 3,
         }
     }
 }
 
-impl Nes {
+impl NesBus {
+    // The PPU pulls NMI while it is in vblank and PPUCTRL bit 7 is set.
+    pub fn nmi_output(&self) -> bool {
+        self.ppu.status & 0x80 != 0 && self.ppu.ctrl & 0x80 != 0
+    }
+
+    // `addr` is one of the eight registers, with the mirrors already folded.
+    pub fn read_register(&mut self, addr: u16) -> u8 {
+        let value = self.peek_register(addr);
+        self.refresh_latch(value, self.driven_bits(addr));
+        match addr {
+            // reading the status ends vblank and resets the write toggle
+            PPU_STATUS => {
+                self.ppu.status &= !STATUS_VBLANK;
+                self.ppu.write_toggle = false;
+            }
+            PPU_DATA => {
+                self.get_vram();
+            }
+            _ => (),
+        }
+        value
+    }
+
+    // Bits the register drives on a read; the others come from the I/O latch.
+    fn driven_bits(&self, addr: u16) -> u8 {
+        match addr {
+            PPU_STATUS => STATUS_VBLANK | STATUS_SPRITE_ZERO_HIT | STATUS_SPRITE_OVERFLOW,
+            OAM_DATA => 0xff,
+            PPU_DATA => match self.ppu.ptr {
+                0x3f00..=0x3fff => 0x3f,
+                _ => 0xff,
+            },
+            _ => 0x00,
+        }
+    }
+
+    pub fn peek_register(&self, addr: u16) -> u8 {
+        let driven = self.driven_bits(addr);
+        self.register_value(addr) & driven | self.io_latch() & !driven
+    }
+
+    // The I/O latch with the bits that decayed since their last refresh cleared.
+    pub fn io_latch(&self) -> u8 {
+        (0..8)
+            .filter(|&bit| self.cycles - self.ppu.latch_refreshed[bit] <= LATCH_DECAY_CYCLES)
+            .fold(0, |latch, bit| latch | self.ppu.io_latch & 1 << bit)
+    }
+
+    fn refresh_latch(&mut self, value: u8, bits: u8) {
+        self.ppu.io_latch = self.ppu.io_latch & !bits | value & bits;
+        for bit in 0..8 {
+            if bits & 1 << bit != 0 {
+                self.ppu.latch_refreshed[bit] = self.cycles
+            }
+        }
+    }
+
+    fn register_value(&self, addr: u16) -> u8 {
+        match addr {
+            PPU_STATUS => self.ppu.status,
+            // bits 2-4 of the sprite attribute byte do not exist and read as 0
+            OAM_DATA => {
+                let value = self.ppu.s_ram[self.ppu.oam_addr as usize];
+                if self.ppu.oam_addr & 0x03 == 0x02 {
+                    value & 0xe3
+                } else {
+                    value
+                }
+            }
+            PPU_DATA => match self.ppu.ptr {
+                0x3f00..=0x3fff => self.ppu.ram[self.ppu.ptr as usize],
+                _ => self.ppu.ppudata_buf,
+            },
+            _ => 0,
+        }
+    }
+
+    pub fn write_register(&mut self, addr: u16, value: u8) {
+        self.refresh_latch(value, 0xff);
+        match addr {
+            PPU_CTRL => self.ppu.ctrl = value,
+            PPU_MASK => self.ppu.mask = value,
+            OAM_ADDR => self.ppu.oam_addr = value,
+            OAM_DATA => {
+                self.ppu.s_ram[self.ppu.oam_addr as usize] = value;
+                self.ppu.oam_addr = self.ppu.oam_addr.wrapping_add(1)
+            }
+            PPU_SCROLL => {
+                self.ppu.scroll[self.ppu.write_toggle as usize] = value;
+                self.ppu.write_toggle = !self.ppu.write_toggle
+            }
+            // the upper byte comes first; VRAM addresses are 14 bits wide
+            PPU_ADDR => {
+                self.ppu.ptr = if self.ppu.write_toggle {
+                    self.ppu.ptr & 0xff00 | value as u16
+                } else {
+                    ((value & 0x3f) as u16) << 8 | self.ppu.ptr & 0x00ff
+                };
+                self.ppu.write_toggle = !self.ppu.write_toggle
+            }
+            PPU_DATA => self.set_vram(value),
+            _ => (),
+        }
+    }
+
     pub fn build_background(&mut self, x: u16, y: u16, b_x: u16, b_y: u16, mesh: &mut MeshBuilder) {
         let sprite_num = self.ppu.ram[0x2000 + b_x as usize + b_y as usize * 0x20];
         let attr = self.ppu.ram[0x23c0 + b_x as usize / 4 + b_y as usize / 4 * 0x08];
@@ -111,9 +250,8 @@ impl Nes {
         }
 
         let mut sprite_bytes = [0; 16];
-        for i in 0..16 {
-            sprite_bytes[i] = self.ppu.ram[sprite_num as usize * 16 + i];
-        }
+        let start = sprite_num as usize * 16;
+        sprite_bytes.copy_from_slice(&self.ppu.ram[start..start + 16]);
         let color0 = (sprite_bytes[y as usize] & (0x01 << (7 - x))) >> (7 - x);
         let color1 = ((sprite_bytes[y as usize + 8] & (0x01 << (7 - x))) >> (7 - x)) << 1;
         let p = pallete * 4 + color0 + color1;
@@ -124,14 +262,153 @@ impl Nes {
         );
         mesh.rectangle(
             graphics::DrawMode::fill(),
-            GridPosition {
-                x,
-                y: y as u16,
-                b_x,
-                b_y,
-            }
-            .into(),
+            GridPosition { x, y, b_x, b_y }.into(),
             graphics::Color::from_rgb(r, g, b),
         );
     }
+
+    pub fn get_vram(&mut self) -> u8 {
+        // Reads go through a one-byte buffer, so each returns the byte of the
+        // previous read. Palette entries skip the buffer, which gets the
+        // nametable byte underneath them instead.
+        let addr = self.ppu.ptr as usize;
+        let value = match addr {
+            0x3f00..=0x3fff => {
+                self.ppu.ppudata_buf = self.ppu.ram[addr - 0x1000];
+                self.ppu.ram[addr]
+            }
+            _ => std::mem::replace(&mut self.ppu.ppudata_buf, self.ppu.ram[addr]),
+        };
+        self.increment_vram_addr();
+        value
+    }
+
+    pub fn set_vram(&mut self, value: u8) {
+        self.ppu.ram[self.ppu.ptr as usize] = value;
+        if self.ppu.ptr == 0x3f00
+            || self.ppu.ptr == 0x3f04
+            || self.ppu.ptr == 0x3f08
+            || self.ppu.ptr == 0x3f0c
+        {
+            self.ppu.ram[self.ppu.ptr as usize + 0x10] = value
+        } else if self.ppu.ptr == 0x3f10
+            || self.ppu.ptr == 0x3f14
+            || self.ppu.ptr == 0x3f18
+            || self.ppu.ptr == 0x3f1c
+        {
+            self.ppu.ram[self.ppu.ptr as usize - 0x10] = value
+        } else if !self.ppu.mirror
+            && ((0x2000 <= self.ppu.ptr && self.ppu.ptr < 0x2400)
+                || (0x2800 <= self.ppu.ptr && self.ppu.ptr < 0x2c00))
+        {
+            self.ppu.ram[self.ppu.ptr as usize + 0x0400] = value
+        } else if self.ppu.mirror && (0x2000 <= self.ppu.ptr && self.ppu.ptr < 0x2800) {
+            self.ppu.ram[self.ppu.ptr as usize + 0x0800] = value
+        }
+        self.increment_vram_addr()
+    }
+
+    fn get_vram_delta(&mut self) -> u16 {
+        if (self.ppu.ctrl & 0x04) > 0 {
+            return 32;
+        }
+        1
+    }
+
+    // VRAM addresses are 14 bits, so stepping past $3FFF wraps to $0000.
+    fn increment_vram_addr(&mut self) {
+        self.ppu.ptr = self.ppu.ptr.wrapping_add(self.get_vram_delta()) & 0x3fff
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bus::Bus;
+    use crate::nes::NesBus;
+    use crate::ppu::{
+        LATCH_DECAY_CYCLES, OAM_ADDR, OAM_DATA, PPU_ADDR, PPU_CTRL, PPU_DATA, PPU_MASK, PPU_SCROLL,
+        PPU_STATUS,
+    };
+
+    #[test]
+    fn test_status_read() {
+        let mut bus = NesBus::default();
+        bus.ppu.status = 0xff;
+        bus.write(PPU_SCROLL, 0x10);
+        assert_eq!(bus.read(PPU_STATUS), 0xf0);
+        assert_eq!(bus.read(PPU_STATUS), 0x70, "reading ends vblank");
+
+        // the toggle was reset, so this is the first write of a pair again
+        bus.write(PPU_SCROLL, 0x20);
+        assert_eq!(bus.ppu.scroll, [0x20, 0x00]);
+    }
+
+    #[test]
+    fn test_oam_read() {
+        let mut bus = NesBus::default();
+        bus.write(OAM_ADDR, 0x01);
+        bus.write(OAM_DATA, 0x11);
+        bus.write(OAM_DATA, 0xff);
+        bus.write(OAM_ADDR, 0x02);
+        assert_eq!(bus.read(OAM_DATA), 0xe3);
+        assert_eq!(bus.read(OAM_DATA), 0xe3, "reads do not move OAMADDR");
+        bus.write(OAM_ADDR, 0x01);
+        assert_eq!(bus.read(OAM_DATA), 0x11);
+    }
+
+    #[test]
+    fn test_buffered_data_read() {
+        let mut bus = NesBus::default();
+        bus.ppu.ram[0x2000..0x2002].copy_from_slice(&[0x12, 0x34]);
+        bus.ppu.ram[0x2f00] = 0x56;
+        bus.ppu.ram[0x3f00] = 0x0f;
+
+        bus.write(PPU_ADDR, 0x20);
+        bus.write(PPU_ADDR, 0x00);
+        bus.read(PPU_DATA);
+        assert_eq!(bus.read(PPU_DATA), 0x12);
+        assert_eq!(bus.read(PPU_DATA), 0x34);
+
+        // palette reads are immediate and fill the buffer from the nametable below
+        bus.write(PPU_ADDR, 0x3f);
+        bus.write(PPU_ADDR, 0x00);
+        assert_eq!(bus.read(PPU_DATA), 0x0f);
+        assert_eq!(bus.ppu.ppudata_buf, 0x56);
+    }
+
+    #[test]
+    fn test_io_latch_decay() {
+        let mut bus = NesBus::default();
+        bus.write(PPU_CTRL, 0xff);
+        assert_eq!(
+            bus.read(PPU_MASK),
+            0xff,
+            "write-only registers read the latch"
+        );
+
+        // a status read refreshes only the bits it drives
+        bus.cycles += LATCH_DECAY_CYCLES / 2;
+        bus.ppu.status = 0xe0;
+        bus.read(PPU_STATUS);
+        bus.cycles += LATCH_DECAY_CYCLES / 2 + 1;
+        assert_eq!(bus.read(PPU_MASK), 0xe0);
+        assert_eq!(bus.peek_register(PPU_STATUS), 0x60);
+    }
+
+    #[test]
+    fn test_vram_addr_wraps() {
+        let mut bus = NesBus::default();
+        bus.write(PPU_ADDR, 0x3f);
+        bus.write(PPU_ADDR, 0xff);
+        bus.write(PPU_DATA, 0x01);
+        bus.write(PPU_DATA, 0x02);
+        assert_eq!(bus.ppu.ram[0x0000], 0x02);
+        assert_eq!(bus.ppu.ptr, 0x0001);
+
+        bus.write(PPU_CTRL, 0x04);
+        bus.write(PPU_ADDR, 0x3f);
+        bus.write(PPU_ADDR, 0xf0);
+        bus.read(PPU_DATA);
+        assert_eq!(bus.ppu.ptr, 0x0010);
+    }
 }
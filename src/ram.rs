@@ -1,11 +1,73 @@
-use crate::nes::Nes;
+use crate::bus::Bus;
+use crate::cpu::Cpu6502;
+use crate::observer::AccessKind;
 
-impl Nes {
-    pub fn fetch_memory8(&self, address: u16) -> u8 {
-        self.ram[address as usize]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusOp {
+    Read,
+    Write,
+}
+
+// One CPU bus cycle, recorded while `Cpu6502::bus_log` is enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusAccess {
+    pub addr: u16,
+    pub value: u8,
+    pub op: BusOp,
+}
+
+impl<B: Bus> Cpu6502<B> {
+    pub fn fetch_memory8(&mut self, address: u16) -> u8 {
+        self.read_bus(address, AccessKind::Read)
+    }
+
+    // Reads the opcode of the next instruction.
+    pub fn fetch_opcode(&mut self, address: u16) -> u8 {
+        self.read_bus(address, AccessKind::Fetch)
+    }
+
+    fn read_bus(&mut self, address: u16, kind: AccessKind) -> u8 {
+        // DMA accesses are logged and observed like the CPU's own, but never
+        // as opcode fetches
+        while let Some(access) = self.bus.dma_cycle(address) {
+            let kind = match access.op {
+                BusOp::Read => AccessKind::Read,
+                BusOp::Write => AccessKind::Write,
+            };
+            self.log_access(access.addr, access.value, access.op);
+            self.notify_observers(access.addr, access.value, kind);
+            self.sample_nmi_line();
+            self.advance_cycles(1)
+        }
+        let value = self.bus.read(address);
+        self.log_access(address, value, BusOp::Read);
+        self.notify_observers(address, value, kind);
+        // reads have side effects too, like $2002 ending vblank
+        self.sample_nmi_line();
+        value
+    }
+
+    // Reads memory without putting the access on the bus, for debugging output.
+    pub fn peek_memory8(&self, address: u16) -> u8 {
+        self.bus.peek(address)
     }
 
     pub fn set_memory8(&mut self, addr: u16, value: u8) {
-        self.ram[addr as usize] = value
+        self.bus.write(addr, value);
+        self.log_access(addr, value, BusOp::Write);
+        self.notify_observers(addr, value, AccessKind::Write);
+        self.sample_nmi_line();
+    }
+
+    // An access can change what the bus drives onto the NMI line.
+    fn sample_nmi_line(&mut self) {
+        let nmi = self.bus.nmi_line();
+        self.set_nmi_line(nmi)
+    }
+
+    fn log_access(&mut self, addr: u16, value: u8, op: BusOp) {
+        if let Some(log) = &mut self.bus_log {
+            log.push(BusAccess { addr, value, op })
+        }
     }
 }
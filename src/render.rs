@@ -36,7 +36,7 @@ impl event::EventHandler for Nes {
             for b_x in 0..WIDTH / 8 {
                 for y in 0..8 {
                     for x in 0..8 {
-                        self.build_background(x, y, b_x, b_y, &mut mesh)
+                        self.bus.build_background(x, y, b_x, b_y, &mut mesh)
                     }
                 }
             }
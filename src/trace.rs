@@ -0,0 +1,255 @@
+use crate::addressing::AddressingMode;
+use crate::bus::Bus;
+use crate::cpu::Cpu6502;
+use crate::disasm::format_operand;
+use crate::instruction::Instruction;
+use crate::opcode::decode;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+// PPU dots per scanline and scanlines per frame
+const DOTS: u64 = 341;
+const SCANLINES: u64 = 262;
+
+// Receives one line per executed instruction in the format of nestest.log:
+//
+// C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+pub struct Tracer {
+    writer: Box<dyn Write>,
+    range: Option<RangeInclusive<u16>>,
+    nintendulator_io: bool,
+}
+
+impl Tracer {
+    pub fn new<W: Write + 'static>(writer: W) -> Self {
+        Tracer {
+            writer: Box::new(writer),
+            range: None,
+            nintendulator_io: false,
+        }
+    }
+
+    pub fn stdout() -> Self {
+        Tracer::new(io::stdout())
+    }
+
+    pub fn file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Tracer::new(BufWriter::new(File::create(path)?)))
+    }
+
+    // Only traces instructions whose address is inside `range`.
+    pub fn pc_range(mut self, range: RangeInclusive<u16>) -> Self {
+        self.range = Some(range);
+        self
+    }
+
+    // Shows $FF for the APU and I/O registers at $4000-$4017 instead of
+    // peeking them, as Nintendulator does in the nestest.log reference trace.
+    pub fn nintendulator_io(mut self, enabled: bool) -> Self {
+        self.nintendulator_io = enabled;
+        self
+    }
+
+    pub fn traces(&self, pc: u16) -> bool {
+        match &self.range {
+            Some(range) => range.contains(&pc),
+            None => true,
+        }
+    }
+
+    // A broken sink should not stop the emulation, so write errors are dropped.
+    pub fn write(&mut self, line: &str) {
+        let _ = writeln!(self.writer, "{}", line);
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        let _ = self.writer.flush();
+    }
+}
+
+impl<B: Bus> Cpu6502<B> {
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer
+    }
+
+    // Emits the trace line for the instruction about to run at `pc`.
+    pub fn trace(&mut self, pc: u16) {
+        if self.tracer.as_ref().is_some_and(|tracer| tracer.traces(pc)) {
+            let line = self.trace_line();
+            if let Some(tracer) = &mut self.tracer {
+                tracer.write(&line)
+            }
+        }
+    }
+
+    // Formats the instruction at PC and the registers before it runs. The PPU
+    // position is derived from the CPU cycle count, three dots per cycle.
+    pub fn trace_line(&self) -> String {
+        let pc = self.cpu.pc;
+        let bytes = [
+            self.peek_memory8(pc),
+            self.peek_memory8(pc.wrapping_add(1)),
+            self.peek_memory8(pc.wrapping_add(2)),
+        ];
+        let (bytes, text) = match decode(bytes[0]) {
+            Some(opcode) => {
+                let bytes = &bytes[..opcode.bytes as usize];
+                let mnemonic = match opcode.instruction {
+                    Instruction::ISC => "ISB".to_string(),
+                    instruction => instruction.mnemonic(),
+                };
+                let unofficial = if opcode.official { ' ' } else { '*' };
+                let operand = self.annotate(opcode.instruction, opcode.mode, &bytes[1..]);
+                let text = format!("{}{} {}", unofficial, mnemonic, operand);
+                (bytes, text)
+            }
+            None => (&bytes[..1], format!(" .db ${:02X}", bytes[0])),
+        };
+        let bytes: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        let dot = self.cpu.cycles * 3;
+        format!(
+            "{:04X}  {:<8} {:<32} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+            pc,
+            bytes.join(" "),
+            text.trim_end(),
+            self.cpu.a,
+            self.cpu.x,
+            self.cpu.y,
+            self.cpu.p.bits(),
+            self.cpu.s,
+            dot / DOTS % SCANLINES,
+            dot % DOTS,
+            self.cpu.cycles
+        )
+    }
+
+    // Adds the effective address and the value stored there to an operand,
+    // the way nestest.log shows them.
+    fn annotate(&self, instruction: Instruction, mode: AddressingMode, operand: &[u8]) -> String {
+        let text = format_operand(mode, self.cpu.pc, operand);
+        let byte = || operand[0];
+        let word = || (operand[1] as u16) << 8 | operand[0] as u16;
+        let nintendulator_io = self
+            .tracer
+            .as_ref()
+            .is_some_and(|tracer| tracer.nintendulator_io);
+        let value = |addr: u16| match addr {
+            0x4000..=0x4017 if nintendulator_io => 0xff,
+            _ => self.peek_memory8(addr),
+        };
+        let zero_page16 = |pointer: u8| {
+            (value(pointer.wrapping_add(1) as u16) as u16) << 8 | value(pointer as u16) as u16
+        };
+        match mode {
+            AddressingMode::ZeroPage => format!("{} = {:02X}", text, value(byte() as u16)),
+            AddressingMode::ZeroPageX | AddressingMode::ZeroPageY => {
+                let index = match mode {
+                    AddressingMode::ZeroPageX => self.cpu.x,
+                    _ => self.cpu.y,
+                };
+                let addr = byte().wrapping_add(index) as u16;
+                format!("{} @ {:02X} = {:02X}", text, addr, value(addr))
+            }
+            AddressingMode::Absolute => match instruction {
+                Instruction::JMP | Instruction::JSR => text,
+                _ => format!("{} = {:02X}", text, value(word())),
+            },
+            AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => {
+                let index = match mode {
+                    AddressingMode::AbsoluteX => self.cpu.x,
+                    _ => self.cpu.y,
+                };
+                let addr = word().wrapping_add(index as u16);
+                format!("{} @ {:04X} = {:02X}", text, addr, value(addr))
+            }
+            AddressingMode::IndirectX => {
+                let pointer = byte().wrapping_add(self.cpu.x);
+                let addr = zero_page16(pointer);
+                format!(
+                    "{} @ {:02X} = {:04X} = {:02X}",
+                    text,
+                    pointer,
+                    addr,
+                    value(addr)
+                )
+            }
+            AddressingMode::IndirectY => {
+                let base = zero_page16(byte());
+                let addr = base.wrapping_add(self.cpu.y as u16);
+                format!(
+                    "{} = {:04X} @ {:04X} = {:02X}",
+                    text,
+                    base,
+                    addr,
+                    value(addr)
+                )
+            }
+            AddressingMode::Indirect => {
+                let pointer = word();
+                let lower = value(pointer);
+                let upper = value(pointer & 0xff00 | (pointer.wrapping_add(1) & 0x00ff));
+                format!("{} = {:02X}{:02X}", text, upper, lower)
+            }
+            _ => text,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Tracer;
+    use crate::asm::assemble;
+    use crate::nes::Nes;
+    use std::io;
+
+    #[test]
+    fn test_trace_line() {
+        let program = assemble(
+            "
+                    JMP start
+            start:  LDX #$02
+                    STX $10
+                    LDA ($0e,X)
+                    .db $04, $10        ; NOP $10
+                    STA $4015
+            ",
+            0xc000,
+        )
+        .unwrap();
+        let mut nes = Nes::default();
+        nes.load_program(&program);
+        nes.bus.ram[0x0011] = 0x02;
+        nes.bus.ram[0x0202] = 0x5a;
+
+        let mut lines = Vec::new();
+        for _ in 0..5 {
+            lines.push(nes.trace_line());
+            nes.step().unwrap();
+        }
+        assert_eq!(
+            lines,
+            vec![
+                "C000  4C 03 C0  JMP $C003                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7",
+                "C003  A2 02     LDX #$02                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 30 CYC:10",
+                "C005  86 10     STX $10 = 00                    A:00 X:02 Y:00 P:24 SP:FD PPU:  0, 36 CYC:12",
+                "C007  A1 0E     LDA ($0E,X) @ 10 = 0202 = 5A    A:00 X:02 Y:00 P:24 SP:FD PPU:  0, 45 CYC:15",
+                "C009  04 10    *NOP $10 = 02                    A:5A X:02 Y:00 P:24 SP:FD PPU:  0, 63 CYC:21",
+            ]
+        );
+
+        // $4015 shows what a read would return, unless asked to match nestest.log
+        assert_eq!(
+            nes.trace_line(),
+            "C00B  8D 15 40  STA $4015 = 00                  A:5A X:02 Y:00 P:24 SP:FD PPU:  0, 72 CYC:24"
+        );
+        nes.set_tracer(Some(Tracer::new(io::sink()).nintendulator_io(true)));
+        assert_eq!(
+            nes.trace_line(),
+            "C00B  8D 15 40  STA $4015 = FF                  A:5A X:02 Y:00 P:24 SP:FD PPU:  0, 72 CYC:24"
+        );
+    }
+}
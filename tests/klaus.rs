@@ -0,0 +1,88 @@
+use rusen::bus::{FlatBus, Outcome};
+use rusen::cpu::{Cpu6502, Variant};
+use std::fs;
+use std::path::Path;
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+// The functional and interrupt tests are 64K images that start at $0400. The
+// success addresses are the ones of the prebuilt binaries in Klaus Dormann's
+// repository.
+const START: u16 = 0x0400;
+const FEEDBACK: u16 = 0xbffc;
+
+// Bruce Clark's decimal test runs from $0200 and leaves 0 in ERROR when every
+// ADC and SBC result matched. It ends on the 65C02 STP opcode, which the
+// test never runs otherwise.
+const DECIMAL_START: u16 = 0x0200;
+const DECIMAL_ERROR: usize = 0x000b;
+const STP: u8 = 0xdb;
+
+fn fixture(file: &str) -> Option<Vec<u8>> {
+    match fs::read(Path::new(FIXTURES).join(file)) {
+        Ok(binary) => Some(binary),
+        Err(_) => {
+            eprintln!("skipping: {} is not in {}", file, FIXTURES);
+            None
+        }
+    }
+}
+
+fn nmos(feedback: Option<u16>) -> Cpu6502<FlatBus> {
+    Cpu6502 {
+        variant: Variant::Nmos6502,
+        ..Cpu6502::new(FlatBus {
+            feedback,
+            ..FlatBus::default()
+        })
+    }
+}
+
+fn run(file: &str, success: u16, feedback: Option<u16>) {
+    let binary = match fixture(file) {
+        Some(binary) => binary,
+        None => return,
+    };
+    let mut cpu = nmos(feedback);
+    cpu.load_binary(&binary, 0x0000, START);
+    match cpu.run_until_trap(Some(success)) {
+        Ok(Outcome::Success) => (),
+        Ok(Outcome::Trapped(pc)) => panic!("{} trapped at ${:04X}: {:?}", file, pc, cpu.cpu),
+        Err(error) => panic!("{} stopped: {}", file, error),
+    }
+}
+
+#[test]
+fn test_functional() {
+    run("6502_functional_test.bin", 0x3469, None)
+}
+
+#[test]
+fn test_interrupt() {
+    run("6502_interrupt_test.bin", 0x06f5, Some(FEEDBACK))
+}
+
+#[test]
+fn test_decimal() {
+    let file = "6502_decimal_test.bin";
+    let binary = match fixture(file) {
+        Some(binary) => binary,
+        None => return,
+    };
+    let mut cpu = nmos(None);
+    cpu.load_binary(&binary, 0x0000, DECIMAL_START);
+    // a build with `end_of_test` changed to a jump to itself traps instead
+    while cpu.peek_memory8(cpu.cpu.pc) != STP {
+        let info = cpu
+            .step()
+            .unwrap_or_else(|error| panic!("{} stopped: {}", file, error));
+        if info.opcode.is_some() && cpu.cpu.pc == info.pc {
+            break;
+        }
+    }
+    assert_eq!(
+        cpu.bus.ram[DECIMAL_ERROR], 0,
+        "{} failed at ${:04X}: {:?}",
+        file, cpu.cpu.pc, cpu.cpu
+    );
+}
@@ -0,0 +1,97 @@
+use rusen::nes::Nes;
+use rusen::trace::Tracer;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+// nestest runs without a PPU when started at $C000 instead of its reset vector
+const AUTOMATION_START: u16 = 0xc000;
+
+const REGISTERS: [&str; 7] = ["A:", "X:", "Y:", "P:", "SP:", "PPU:", "CYC:"];
+
+// Reads a register field like `A:00` or `PPU:  0, 21` from a trace line.
+fn field<'a>(line: &'a str, name: &str) -> &'a str {
+    let start = match line.find(&format!(" {}", name)) {
+        Some(start) => start + name.len() + 1,
+        None => return "",
+    };
+    let end = REGISTERS
+        .iter()
+        .filter_map(|next| line[start..].find(&format!(" {}", next)))
+        .min()
+        .map_or(line.len(), |end| start + end);
+    line[start..end].trim()
+}
+
+fn divergence(number: usize, expected: &str, actual: &str) -> String {
+    let mut report = format!(
+        "trace diverges from nestest.log at line {}\nexpected: {}\nactual:   {}\n",
+        number, expected, actual
+    );
+    for name in REGISTERS.iter() {
+        let (expected, actual) = (field(expected, name), field(actual, name));
+        if expected != actual {
+            report += &format!("  {:<4} expected {}, got {}\n", name, expected, actual);
+        }
+    }
+    report
+}
+
+#[test]
+fn test_nestest() {
+    let fixtures = Path::new(FIXTURES);
+    let (rom, log) = match (
+        fs::read(fixtures.join("nestest.nes")),
+        fs::read_to_string(fixtures.join("nestest.log")),
+    ) {
+        (Ok(rom), Ok(log)) => (rom, log),
+        _ => {
+            eprintln!(
+                "skipping nestest: nestest.nes and nestest.log are not in {}",
+                FIXTURES
+            );
+            return;
+        }
+    };
+
+    let mut nes = Nes::default();
+    // lines are compared through `trace_line`, so the tracer only sets the format
+    nes.set_tracer(Some(Tracer::new(io::sink()).nintendulator_io(true)));
+    nes.load(rom);
+    nes.initialize();
+    nes.reset();
+    nes.cpu.pc = AUTOMATION_START;
+
+    for (index, expected) in log.lines().enumerate() {
+        let expected = expected.trim_end();
+        let actual = nes.trace_line();
+        if actual != expected {
+            panic!("{}", divergence(index + 1, expected, &actual));
+        }
+        if let Err(error) = nes.step() {
+            panic!("CPU halted at line {}: {}\n{}", index + 1, error, actual);
+        }
+    }
+
+    // nestest leaves the number of the first failed official and unofficial
+    // opcode test in $02 and $03
+    let results = (nes.fetch_memory8(0x02), nes.fetch_memory8(0x03));
+    assert_eq!(
+        results,
+        (0x00, 0x00),
+        "nestest reported failures: $02={:02X} $03={:02X}",
+        results.0,
+        results.1
+    );
+}
+
+#[test]
+fn test_divergence_report() {
+    let expected = "C72A  D0 E0     BNE $C70C                       A:00 X:00 Y:00 P:27 SP:FB PPU: 30, 82 CYC:3398";
+    let actual = "C72A  D0 E0     BNE $C70C                       A:00 X:00 Y:00 P:25 SP:FB PPU: 30, 82 CYC:3398";
+    assert_eq!(field(expected, "PPU:"), "30, 82");
+    assert_eq!(field(expected, "P:"), "27");
+    assert!(divergence(7, expected, actual).ends_with("  P:   expected 27, got 25\n"));
+}
@@ -0,0 +1,184 @@
+use rusen::bus::FlatBus;
+use rusen::cpu::{Cpu, Cpu6502};
+use rusen::flag::Status;
+use rusen::instruction::Instruction;
+use rusen::opcode::decode;
+use rusen::ram::{BusAccess, BusOp};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+// One JSON file per opcode, named after it in lowercase hex (`a9.json`), in
+// the format of https://github.com/SingleStepTests/65x02 (nes6502).
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/single_step");
+
+// failing tests shown per opcode
+const REPORTED: usize = 3;
+
+#[derive(Deserialize)]
+struct Test {
+    name: String,
+    initial: State,
+    #[serde(rename = "final")]
+    expected: State,
+    cycles: Vec<(u16, u8, String)>,
+}
+
+#[derive(Deserialize)]
+struct State {
+    pc: u16,
+    s: u8,
+    a: u8,
+    x: u8,
+    y: u8,
+    p: u8,
+    ram: Vec<(u16, u8)>,
+}
+
+// Runs a single test and returns what did not match.
+fn run(test: &Test) -> Vec<String> {
+    let initial = &test.initial;
+    let mut machine: Cpu6502<FlatBus> = Cpu6502 {
+        cpu: Cpu {
+            a: initial.a,
+            x: initial.x,
+            y: initial.y,
+            s: initial.s,
+            p: Status::from_bits(initial.p),
+            pc: initial.pc,
+            ..Cpu::default()
+        },
+        bus_log: Some(Vec::new()),
+        ..Cpu6502::default()
+    };
+    for (addr, value) in &initial.ram {
+        machine.bus.ram[*addr as usize] = *value;
+    }
+
+    let mut errors = Vec::new();
+    match machine.step() {
+        Ok(info) if info.cycles as usize != test.cycles.len() => errors.push(format!(
+            "cycles: expected {}, got {}",
+            test.cycles.len(),
+            info.cycles
+        )),
+        Ok(_) => (),
+        Err(error) => errors.push(format!("step failed: {}", error)),
+    }
+
+    let expected = &test.expected;
+    // P has no B bit and its unused bit always reads as set
+    let registers = [
+        ("pc", expected.pc, machine.cpu.pc),
+        ("s", expected.s as u16, machine.cpu.s as u16),
+        ("a", expected.a as u16, machine.cpu.a as u16),
+        ("x", expected.x as u16, machine.cpu.x as u16),
+        ("y", expected.y as u16, machine.cpu.y as u16),
+        (
+            "p",
+            (expected.p | 0x30) as u16,
+            (machine.cpu.p.bits() | 0x30) as u16,
+        ),
+    ];
+    for (name, expected, actual) in registers.iter() {
+        if expected != actual {
+            errors.push(format!(
+                "{}: expected {:02X}, got {:02X}",
+                name, expected, actual
+            ));
+        }
+    }
+    for (addr, value) in &expected.ram {
+        let actual = machine.bus.ram[*addr as usize];
+        if actual != *value {
+            errors.push(format!(
+                "ram[${:04X}]: expected {:02X}, got {:02X}",
+                addr, value, actual
+            ));
+        }
+    }
+
+    let log = machine.bus_log.take().unwrap_or_default();
+    let expected: Vec<BusAccess> = test
+        .cycles
+        .iter()
+        .map(|(addr, value, op)| BusAccess {
+            addr: *addr,
+            value: *value,
+            op: if op == "write" {
+                BusOp::Write
+            } else {
+                BusOp::Read
+            },
+        })
+        .collect();
+    if let Some(cycle) =
+        (0..expected.len().max(log.len())).find(|i| expected.get(*i) != log.get(*i))
+    {
+        errors.push(format!(
+            "bus cycle {}: expected {}, got {}",
+            cycle + 1,
+            describe(expected.get(cycle)),
+            describe(log.get(cycle))
+        ));
+    }
+    errors
+}
+
+fn describe(access: Option<&BusAccess>) -> String {
+    match access {
+        Some(access) => format!(
+            "{:?} ${:04X} = {:02X}",
+            access.op, access.addr, access.value
+        ),
+        None => "nothing".to_string(),
+    }
+}
+
+#[test]
+fn test_single_step() {
+    let fixtures = Path::new(FIXTURES);
+    let mut files = 0;
+    let mut report = String::new();
+    for code in 0..=0xffu8 {
+        // KIL halts with an error instead of running cycles; test_kil_jams covers it
+        let opcode = match decode(code) {
+            Some(opcode) if opcode.instruction != Instruction::KIL => opcode,
+            _ => continue,
+        };
+        let json = match fs::read_to_string(fixtures.join(format!("{:02x}.json", code))) {
+            Ok(json) => json,
+            Err(_) => continue,
+        };
+        let tests: Vec<Test> = serde_json::from_str(&json).unwrap();
+        files += 1;
+
+        let failures: Vec<(&Test, Vec<String>)> = tests
+            .iter()
+            .map(|test| (test, run(test)))
+            .filter(|(_, errors)| !errors.is_empty())
+            .collect();
+        if failures.is_empty() {
+            continue;
+        }
+        report += &format!(
+            "${:02X} {:?} {:?}: {} of {} tests failed\n",
+            code,
+            opcode.instruction,
+            opcode.mode,
+            failures.len(),
+            tests.len()
+        );
+        for (test, errors) in failures.iter().take(REPORTED) {
+            report += &format!("  {}\n", test.name);
+            for error in errors {
+                report += &format!("    {}\n", error);
+            }
+        }
+    }
+
+    if files == 0 {
+        eprintln!("skipping single step tests: no test files in {}", FIXTURES);
+    }
+    assert!(report.is_empty(), "\n{}", report);
+}